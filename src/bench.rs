@@ -0,0 +1,74 @@
+/*
+ * Counts the allocations made while evaluating a few recursive programs, failing if a program makes
+ * more than it used to. See the counts with `cargo test allocations -- --nocapture`.
+ */
+use crate::{evaluator, heap, parser, scanner};
+
+// The name of each program, the program and at most how many allocations it may make
+const PROGRAMS: &[(&str, &str, usize)] = &[
+    (
+        "countdown",
+        "(define (countdown n acc) (cond ((= n 0) acc) (t (countdown (- n 1) (+ acc 1)))))
+         (countdown 100 0)",
+        8000,
+    ),
+    (
+        "power",
+        "(define (pw x n) (cond ((= n 0) 1) (t (* x (pw x (- n 1))))))
+         (pw 3 60)",
+        4800,
+    ),
+    (
+        "residual power",
+        "(define (pw x n) (cond ((= n 0) 1) (t (* x (pw x (- n 1))))))
+         (pw y 30)",
+        2000,
+    ),
+    (
+        "closures",
+        "(define (adder k) (lambda (x) (+ x k)))
+         (define (sum-adders n acc) (cond ((= n 0) acc) (t (sum-adders (- n 1) ((adder n) acc)))))
+         (sum-adders 80 0)",
+        6500,
+    ),
+    (
+        "nested scopes",
+        "(define (outer a) (lambda (b) (lambda (c) (guard (e (t a)) (+ a b c)))))
+         (define (sum-scopes n acc) (cond ((= n 0) acc) (t (sum-scopes (- n 1) (((outer n) 1) acc)))))
+         (sum-scopes 80 0)",
+        2200,
+    ),
+];
+
+/* The number of allocations and allocated bytes of evaluating a program, not counting parsing it. */
+fn measure(program: &str) -> (usize, usize) {
+    let mut eval = evaluator::Evaluator::new();
+    let expressions = parser::parse(scanner::scan(program));
+    let before = (heap::allocations(), heap::allocated());
+    evaluator::evaluate(expressions, &mut eval).unwrap();
+    (heap::allocations() - before.0, heap::allocated() - before.1)
+}
+
+#[test]
+fn allocations() {
+    // Deep recursion needs more stack than a test thread has
+    let run = || {
+        for (name, program, most) in PROGRAMS {
+            let (count, bytes) = measure(program);
+            println!("{:<16} {:>10} allocations {:>12} bytes", name, count, bytes);
+            assert!(
+                count <= *most,
+                "{} made {} allocations, at most {} expected",
+                name,
+                count,
+                most
+            );
+        }
+    };
+    std::thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+}
@@ -0,0 +1,331 @@
+use crate::evaluator::{self, Evaluator};
+use crate::records;
+use crate::symbols::{self, Symbol};
+use crate::types::{Expression, LispError, Params};
+use std::rc::Rc;
+
+// Instructions of the virtual machine, which works on a stack of values
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Constant(usize),             // Pushes a constant of the chunk
+    Local(Symbol, usize, usize), // Pushes a parameter by its lexical address
+    Variable(Symbol),            // Pushes a global or free variable, looked up by name
+    Define(Symbol),              // Binds a global variable to the value on top, leaving it there
+    Closure(usize), // Pops the defaults of a function of the chunk and pushes a closure of it
+    Builtin(Symbol, usize), // Pops that many arguments and pushes what the builtin returns
+    Call(usize),    // Pops that many arguments and the function below them and calls it
+    TailCall(usize), // Likewise, the call replaces the current one
+    Jump(usize),    // Continues at an instruction
+    JumpIfFalse(usize), // Pops a value and continues at an instruction if it is false
+    Guard(usize), // Pops a handler, an error until the matching EndGuard continues at the instruction
+    EndGuard,
+    Pop,
+    Return,
+}
+
+// Compiled code with the constants and functions it refers to
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<Expression>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+// A compiled function, a call to a closure of it runs the chunk with the arguments bound
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub params: Params, // With the defaults still to be evaluated when a closure is made
+    pub body: Rc<Expression>, // The source of the body, its closures share it so their code can be found again
+    pub chunk: Chunk,
+}
+
+struct Compiler<'a> {
+    chunk: Chunk,
+    scopes: Vec<Vec<Symbol>>, // The parameters of the functions being compiled, innermost last
+    eval: &'a Evaluator,
+}
+
+/* Compiles top level expressions to a function without parameters, returning the value of the last one. */
+pub fn compile(expressions: &[Expression], eval: &Evaluator) -> Result<Function, LispError> {
+    let mut compiler = Compiler {
+        chunk: Chunk::default(),
+        scopes: Vec::new(),
+        eval,
+    };
+    for (i, e) in expressions.iter().enumerate() {
+        if i > 0 {
+            compiler.emit(Op::Pop);
+        }
+        compiler.expression(e, false)?;
+    }
+    if expressions.is_empty() {
+        compiler.constant(Expression::Nil);
+    }
+    compiler.emit(Op::Return);
+    Ok(Function {
+        params: Params::default(),
+        body: Rc::new(Expression::Nil),
+        chunk: compiler.chunk,
+    })
+}
+
+/*
+ * Compiles the body of a function nested in functions with the parameters of `scopes`.
+ * The variables of any other scope are looked up by name when the function runs.
+ */
+pub fn compile_function(
+    params: &Params,
+    body: &Rc<Expression>,
+    mut scopes: Vec<Vec<Symbol>>,
+    eval: &Evaluator,
+) -> Result<Function, LispError> {
+    scopes.push(params.names().copied().collect());
+    let mut compiler = Compiler {
+        chunk: Chunk::default(),
+        scopes,
+        eval,
+    };
+    compiler.expression(body, true)?;
+    compiler.emit(Op::Return);
+    Ok(Function {
+        params: params.clone(),
+        body: body.clone(),
+        chunk: compiler.chunk,
+    })
+}
+
+impl Compiler<'_> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.code.len() - 1
+    }
+
+    /* Points the jump at `at` to the next instruction. */
+    fn patch(&mut self, at: usize) {
+        let next = self.chunk.code.len();
+        if let Op::Jump(target) | Op::JumpIfFalse(target) | Op::Guard(target) =
+            &mut self.chunk.code[at]
+        {
+            *target = next;
+        }
+    }
+
+    fn constant(&mut self, e: Expression) {
+        self.chunk.constants.push(e);
+        self.emit(Op::Constant(self.chunk.constants.len() - 1));
+    }
+
+    /* Compiles an expression, a call in tail position replaces the current one instead of returning to it. */
+    fn expression(&mut self, e: &Expression, tail: bool) -> Result<(), LispError> {
+        match e {
+            Expression::Symbol(s) | Expression::Local(s, ..) => self.variable(*s),
+            Expression::List(v) if !v.is_empty() => return self.list(e, v, tail),
+            Expression::Lambda(params, body, _) => {
+                // Already evaluated, for example by the tree walker
+                for (_, default) in params.optional.iter().chain(params.key.iter()) {
+                    self.constant(default.clone());
+                }
+                return self.closure(params, body);
+            }
+            _ => self.constant(e.clone()),
+        }
+        Ok(())
+    }
+
+    fn variable(&mut self, s: Symbol) {
+        for (depth, names) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = names.iter().position(|name| *name == s) {
+                self.emit(Op::Local(s, depth, index));
+                return;
+            }
+        }
+        self.emit(Op::Variable(s));
+    }
+
+    /*
+     * Special forms are found by their name like in the tree walker, even where a parameter shadows them.
+     * Calls to builtins check when they run whether the program has bound the name itself.
+     */
+    fn list(&mut self, e: &Expression, v: &[Expression], tail: bool) -> Result<(), LispError> {
+        let Expression::Symbol(head) = v[0] else {
+            return self.call(v, tail);
+        };
+        match head {
+            symbols::DEFINE => self.define(e, v),
+            symbols::COND => self.cond(e, v, tail),
+            symbols::LAMBDA => match parse_lambda(v) {
+                Some(params) => self.closure_of(&params, &v[2]),
+                None => {
+                    self.constant(e.clone());
+                    Ok(())
+                }
+            },
+            symbols::DEFINE_RECORD_TYPE => self.define_record(e, v),
+            symbols::GUARD => self.guard(e, v),
+            symbols::SPECIALIZE | symbols::DECLARE_SYMBOLIC => Err(LispError::Type(
+                e.clone(),
+                "a form the virtual machine can run".to_string(),
+            )),
+            s if evaluator::is_builtin(s, self.eval) => {
+                for arg in v[1..].iter() {
+                    self.expression(arg, false)?;
+                }
+                self.emit(Op::Builtin(s, v.len() - 1));
+                Ok(())
+            }
+            _ => self.call(v, tail),
+        }
+    }
+
+    fn call(&mut self, v: &[Expression], tail: bool) -> Result<(), LispError> {
+        for e in v.iter() {
+            self.expression(e, false)?;
+        }
+        if tail {
+            self.emit(Op::TailCall(v.len() - 1));
+        } else {
+            self.emit(Op::Call(v.len() - 1));
+        }
+        Ok(())
+    }
+
+    /* Compiles a function nested in the current one, its defaults are evaluated where the closure is made. */
+    fn closure_of(&mut self, params: &Params, body: &Expression) -> Result<(), LispError> {
+        for (_, default) in params.optional.iter().chain(params.key.iter()) {
+            self.expression(default, false)?;
+        }
+        self.closure(params, &Rc::new(body.clone()))
+    }
+
+    fn closure(&mut self, params: &Params, body: &Rc<Expression>) -> Result<(), LispError> {
+        let function = compile_function(params, body, self.scopes.clone(), self.eval)?;
+        self.chunk.functions.push(Rc::new(function));
+        self.emit(Op::Closure(self.chunk.functions.len() - 1));
+        Ok(())
+    }
+
+    /* Malformed definitions stay as they are, like in the tree walker. */
+    fn define(&mut self, e: &Expression, v: &[Expression]) -> Result<(), LispError> {
+        match v {
+            [_, Expression::Symbol(name), value, ..] => {
+                self.expression(value, false)?;
+                self.emit(Op::Define(*name));
+            }
+            [_, Expression::List(header), body, ..] => {
+                let params = parse_params(&header[1..]);
+                let (Some(Expression::Symbol(name)), Some(params)) = (header.first(), params)
+                else {
+                    self.constant(e.clone());
+                    return Ok(());
+                };
+                self.closure_of(&params, body)?;
+                self.emit(Op::Define(*name));
+            }
+            _ => self.constant(e.clone()),
+        }
+        Ok(())
+    }
+
+    /*
+     * Compiles the clauses to tests jumping over their expression when false.
+     * Any value but false takes a clause, the tree walker would leave a test that isn't a boolean undecided.
+     */
+    fn cond(&mut self, e: &Expression, v: &[Expression], tail: bool) -> Result<(), LispError> {
+        if v.len() < 2 {
+            self.constant(e.clone());
+            return Ok(());
+        }
+        let mut ends = Vec::new();
+        for clause in v[1..].iter() {
+            let Expression::List(clause) = clause else {
+                return Err(LispError::Type(
+                    e.clone(),
+                    "clauses of a test and an expression".to_string(),
+                ));
+            };
+            let [test, branch] = clause.as_slice() else {
+                return Err(LispError::Type(
+                    e.clone(),
+                    "clauses of a test and an expression".to_string(),
+                ));
+            };
+            self.expression(test, false)?;
+            let skip = self.emit(Op::JumpIfFalse(0));
+            self.expression(branch, tail)?;
+            ends.push(self.emit(Op::Jump(0)));
+            self.patch(skip);
+        }
+        self.constant(Expression::Nil);
+        for end in ends {
+            self.patch(end);
+        }
+        Ok(())
+    }
+
+    /*
+     * Compiles the clauses to a handler taking what was raised, which raises it again if no clause takes it.
+     * An error in the body leaves the stack as it was before the body and calls the handler with it:
+     *     closure handler; guard catch; body...; end-guard; jump end; catch: call 1; end:
+     */
+    fn guard(&mut self, e: &Expression, v: &[Expression]) -> Result<(), LispError> {
+        let Some((variable, clauses)) = evaluator::parse_guard(v) else {
+            return Err(LispError::Type(
+                e.clone(),
+                "(guard (variable clause...) body...)".to_string(),
+            ));
+        };
+        let reraise = Expression::list(vec![
+            Expression::Boolean(true),
+            Expression::list(vec![
+                Expression::Symbol(Symbol::new("raise")),
+                Expression::Symbol(variable),
+            ]),
+        ]);
+        let mut cond = vec![Expression::Symbol(symbols::COND)];
+        cond.extend(clauses.iter().cloned());
+        cond.push(reraise);
+        let params = parse_params(&[Expression::Symbol(variable)]).unwrap();
+        self.closure(&params, &Rc::new(Expression::list(cond)))?;
+        let guard = self.emit(Op::Guard(0));
+        for (i, e) in v[2..].iter().enumerate() {
+            if i > 0 {
+                self.emit(Op::Pop);
+            }
+            self.expression(e, false)?;
+        }
+        self.emit(Op::EndGuard);
+        let end = self.emit(Op::Jump(0));
+        self.patch(guard);
+        self.emit(Op::Call(1));
+        self.patch(end);
+        Ok(())
+    }
+
+    fn define_record(&mut self, e: &Expression, v: &[Expression]) -> Result<(), LispError> {
+        let Some(procedures) = records::define(v) else {
+            return Err(LispError::Type(
+                e.clone(),
+                "(define-record-type name (constructor field...) predicate (field accessor [modifier])...)"
+                    .to_string(),
+            ));
+        };
+        for (name, procedure) in procedures {
+            self.expression(&procedure, false)?;
+            self.emit(Op::Define(name));
+            self.emit(Op::Pop);
+        }
+        self.constant(Expression::Nil);
+        Ok(())
+    }
+}
+
+fn parse_lambda(v: &[Expression]) -> Option<Params> {
+    match v {
+        [_, params, _] => evaluator::parse_params(params),
+        _ => None,
+    }
+}
+
+fn parse_params(v: &[Expression]) -> Option<Params> {
+    evaluator::parse_params(&Expression::list(v.to_vec()))
+}
@@ -0,0 +1,128 @@
+use crate::gc;
+use crate::records::{Record, RecordType};
+use crate::symbols::Symbol;
+use crate::types::{Expression, LispError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/*
+ * Raising and catching errors. `(error message irritant...)` raises a condition, `(raise value)`
+ * raises any value, and `guard` catches them. Errors of the builtins are caught as conditions too,
+ * with a kind naming the error, so `(condition-kind e)` of `(/ 1 0)` is `'division-by-zero`.
+ * Conditions are records of the type `condition`, with the fields kind, message and irritants.
+ */
+pub const FUNCTIONS: &[&str] = &[
+    "error",
+    "raise",
+    "condition?",
+    "condition-kind",
+    "condition-message",
+    "condition-irritants",
+];
+
+const FIELDS: &[&str] = &["kind", "message", "irritants"];
+
+thread_local! {
+    // Builtin, so no record type a program defines is the type of the conditions
+    static CONDITION: Rc<RecordType> = Rc::new(RecordType {
+        name: "condition".to_string(),
+        fields: FIELDS.iter().map(|field| field.to_string()).collect(),
+    });
+}
+
+/* The record type of the conditions. */
+pub fn condition_type() -> Rc<RecordType> {
+    CONDITION.with(Rc::clone)
+}
+
+/* The smallest and largest number of arguments of a builtin, None if there is no limit. */
+pub fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "error" => (1, None),
+        _ => (1, Some(1)),
+    }
+}
+
+pub fn new(kind: &str, message: String, irritants: Vec<Expression>) -> Expression {
+    let condition = Expression::Record(Rc::new(Record {
+        kind: condition_type(),
+        values: RefCell::new(vec![
+            Expression::quote(Expression::Symbol(Symbol::new(kind))),
+            Expression::String(message),
+            Expression::list_or_nil(irritants),
+        ]),
+    }));
+    gc::track_value(&condition);
+    condition
+}
+
+fn is_condition(e: &Expression) -> bool {
+    matches!(e, Expression::Record(r) if r.kind == condition_type())
+}
+
+/* What a guard catches for an error, None if it can't be caught. */
+pub fn from_error(err: &LispError) -> Option<Expression> {
+    let (kind, irritant) = match err {
+        LispError::Raised(value) => return Some(value.clone()),
+        // Limits and Ctrl-C stop the program, a handler could only run into them again
+        LispError::Interrupted | LispError::Limit(..) => return None,
+        LispError::DivisionByZero(e) => ("division-by-zero", e.clone()),
+        LispError::Arity(e, _) => ("arity", e.clone()),
+        LispError::Type(e, _) => ("type", e.clone()),
+        LispError::Range(e, _) => ("range", e.clone()),
+        LispError::Unbound(name, _) => ("unbound", Expression::Symbol(Symbol::new(name))),
+    };
+    // Quoted, so the call an error was raised in is data and not a call left to evaluate
+    Some(new(
+        kind,
+        err.to_string(),
+        vec![Expression::quote(irritant)],
+    ))
+}
+
+/* Describes a raised value nothing caught, a condition by its message. */
+pub fn describe(value: &Expression) -> String {
+    let values = match value {
+        Expression::Record(r) if is_condition(value) => r.values.borrow(),
+        _ => return format!("uncaught {}", value),
+    };
+    let message = match &values[1] {
+        Expression::String(message) => message.clone(),
+        e => e.to_string(),
+    };
+    // The errors of the builtins already name what they were raised in
+    match (&values[0], &values[2]) {
+        (Expression::Quote(kind), Expression::List(irritants))
+            if **kind == Expression::Symbol(Symbol::new("error")) && !irritants.is_empty() =>
+        {
+            let irritants: Vec<String> = irritants.iter().map(|e| e.to_string()).collect();
+            format!("{}: {}", message, irritants.join(" "))
+        }
+        _ => message,
+    }
+}
+
+/* Calls the builtin `name`, with the arguments already checked. */
+pub fn call(name: &str, args: &[Expression], call: &Expression) -> Result<Expression, LispError> {
+    match name {
+        "error" => match &args[0] {
+            Expression::String(message) => Err(LispError::Raised(new(
+                "error",
+                message.clone(),
+                args[1..].to_vec(),
+            ))),
+            _ => Err(LispError::Type(call.clone(), "a message".to_string())),
+        },
+        "raise" => Err(LispError::Raised(args[0].clone())),
+        "condition?" => Ok(Expression::Boolean(is_condition(&args[0]))),
+        _ => {
+            let r = match &args[0] {
+                Expression::Record(r) if is_condition(&args[0]) => r,
+                _ => return Err(LispError::Type(call.clone(), "a condition".to_string())),
+            };
+            let field = &name["condition-".len()..];
+            let i = FIELDS.iter().position(|f| *f == field).unwrap();
+            Ok(r.values.borrow()[i].clone())
+        }
+    }
+}
@@ -1,352 +1,1831 @@
-use crate::types::{self, Environment};
-use std::fmt::Debug;
-use std::thread::sleep;
-use std::{collections::HashSet, time::Duration};
+use crate::conditions;
+use crate::gc;
+use crate::heap;
+use crate::interrupt;
+use crate::maps;
+use crate::math;
+use crate::number::Number;
+use crate::records::{self, Access};
+use crate::specializer::{self, MAX_UNFOLD_DEPTH, Specializer};
+use crate::stack;
+use crate::strings;
+use crate::symbols::{self, Symbol};
+use crate::types::{self, Environment, LispError, Params, Scope, check_arity};
+use crate::vectors;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use types::Expression;
 
-struct Evaluator {
+pub struct Evaluator {
     index: usize,
-    env: Environment,
+    pub env: Environment,
+    pub curry: bool, // Under-applied calls return a specialised function instead of failing
+    specializer: Option<Specializer>, // Set while a `specialize` is running
+    pub unfold_budget: usize, // How many calls with symbolic arguments each top level evaluation may unfold
+    unfolded: usize,          // Those unfolded by the current evaluation
+    unfolding: Vec<(Symbol, Vec<Option<Expression>>)>, // Those calls, as the function and its static arguments
+    pub strict: bool,          // Only declared free variables may stay symbolic
+    symbolic: HashSet<Symbol>, // Free variables declared with `declare-symbolic`
+    deferred: usize, // How many function bodies or undecided branches we are reducing ahead of time
+    builtins: HashMap<Symbol, Builtin>, // The builtins from the other modules, by the reducer handling them
+    pub fuel: Option<usize>,            // Steps each top level evaluation may take
+    pub max_depth: Option<usize>,       // How deeply calls may nest
+    pub max_heap: Option<usize>, // Bytes an evaluation may allocate on top of what was in use before it
+    pub interruptible: bool,     // Ctrl-C stops the evaluation, in the REPL
+    steps: usize,                // Taken by the current evaluation
+    depth: usize,                // Calls being run
+    heap_base: usize,            // Bytes in use when the current evaluation started
 }
 
+// Which reducer a builtin from the other modules goes to
+#[derive(Clone, Copy)]
+enum Builtin {
+    Math,
+    String,
+    Collection,
+    Predicate,
+    Memory,
+    Condition,
+}
+
+// Default for the unfolding budget
+pub const UNFOLD_BUDGET: usize = 32;
+
+// Functions handled by the evaluator itself, the other builtins have their own modules
+const BUILTINS: &[Symbol] = &[
+    symbols::ADD,
+    symbols::SUBTRACT,
+    symbols::MULTIPLY,
+    symbols::DIVIDE,
+    symbols::EQUAL,
+];
+
+// Forms that don't evaluate their arguments like a function call
+const SPECIAL_FORMS: &[Symbol] = &[
+    symbols::DEFINE,
+    symbols::COND,
+    symbols::LAMBDA,
+    symbols::SPECIALIZE,
+    symbols::DECLARE_SYMBOLIC,
+    symbols::DEFINE_RECORD_TYPE,
+    symbols::GUARD,
+];
+
+// Predicates on the type of a value
+const PREDICATES: &[&str] = &[
+    "number?",
+    "integer?",
+    "string?",
+    "symbol?",
+    "list?",
+    "pair?",
+    "boolean?",
+    "procedure?",
+    "null?",
+    "vector?",
+    "hash?",
+];
+
 impl Evaluator {
-    fn new() -> Evaluator {
+    pub fn new() -> Evaluator {
+        let mut env = Environment::new();
+        for (name, value) in math::CONSTANTS {
+            env.global_push(Symbol::new(name), Expression::Number(Number::Real(*value)));
+        }
+        let collections = vectors::FUNCTIONS.iter().chain(maps::FUNCTIONS);
+        let predicates = PREDICATES.iter().chain(&["type-of", "symbolic?"]);
+        let builtins = math::FUNCTIONS
+            .iter()
+            .map(|name| (name, Builtin::Math))
+            .chain(
+                strings::FUNCTIONS
+                    .iter()
+                    .map(|name| (name, Builtin::String)),
+            )
+            .chain(collections.map(|name| (name, Builtin::Collection)))
+            .chain(predicates.map(|name| (name, Builtin::Predicate)))
+            .chain(gc::FUNCTIONS.iter().map(|name| (name, Builtin::Memory)))
+            .chain(
+                conditions::FUNCTIONS
+                    .iter()
+                    .map(|name| (name, Builtin::Condition)),
+            )
+            .map(|(name, builtin)| (Symbol::new(name), builtin))
+            .collect();
         Evaluator {
             index: 0,
-            env: Environment::new(),
+            env,
+            curry: false,
+            specializer: None,
+            unfold_budget: UNFOLD_BUDGET,
+            unfolded: 0,
+            unfolding: Vec::new(),
+            strict: false,
+            symbolic: HashSet::new(),
+            deferred: 0,
+            builtins,
+            fuel: None,
+            max_depth: None,
+            max_heap: None,
+            interruptible: false,
+            steps: 0,
+            depth: 0,
+            heap_base: 0,
         }
     }
 }
 
-fn reduce_addition(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    let mut vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
-    let (numbers, mut symbols): (Vec<_>, Vec<_>) = vexp
-        .drain(1..)
-        .partition(|e| matches!(e, Expression::Number(_)));
+/* Splits evaluated arguments into their numeric values and the residual, non-numeric expressions. */
+fn partition_numbers(vexp: Vec<Expression>) -> (Vec<Number>, Vec<Expression>) {
+    let mut numbers = Vec::new();
+    let mut symbols = Vec::new();
+    for e in vexp {
+        match e {
+            Expression::Number(n) => numbers.push(n),
+            _ => symbols.push(e),
+        }
+    }
+    (numbers, symbols)
+}
 
-    let res: f64 = numbers.iter().fold(0.0, |mut acc, e| {
-        if let Expression::Number(n) = e {
-            acc += n;
+/*
+ * Checks a call to an arithmetic or math builtin: the number of arguments, between `min` and `max`,
+ * and that each is a number once the call runs with all of them known. Reduced ahead of time, the
+ * call is left for when it runs, as the code may never get there.
+ */
+fn check_numeric(
+    vexp: &[Expression],
+    (min, max): (usize, Option<usize>),
+    eval: &Evaluator,
+) -> Result<(), LispError> {
+    let args = &vexp[1..];
+    check_arity(min, max, args, || Expression::list(vexp.to_vec()))?;
+    let ahead = eval.deferred > 0 || eval.specializer.is_some();
+    if ahead || !args.iter().all(specializer::is_static) {
+        return Ok(());
+    }
+    if args.iter().any(|e| !matches!(e, Expression::Number(_))) {
+        return Err(LispError::Type(
+            Expression::list(vexp.to_vec()),
+            "a number".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/* Splices the arguments of nested residual calls to an associative operator into the outer call. */
+fn flatten(op: Symbol, vexp: Vec<Expression>) -> Vec<Expression> {
+    let mut flat = Vec::with_capacity(vexp.len());
+    for e in vexp {
+        match e {
+            Expression::List(v) if matches!(v.first(), Some(Expression::Symbol(s)) if *s == op) => {
+                flat.extend(v[1..].iter().cloned())
+            }
+            _ => flat.push(e),
         }
-        acc
-    });
+    }
+    flat
+}
+
+/* Evaluates every expression of a call, including the operator itself. */
+fn evaluate_all(vexp: &[Expression], eval: &mut Evaluator) -> Result<Vec<Expression>, LispError> {
+    vexp.iter().map(|e| evaluate_expression(e, eval)).collect()
+}
+
+fn reduce_addition(mut vexp: Vec<Expression>) -> Result<Expression, LispError> {
+    let (numbers, mut symbols) =
+        partition_numbers(flatten(symbols::ADD, vexp.drain(1..).collect()));
+
+    let res = numbers.iter().fold(Number::from(0), |acc, n| acc.add(n));
 
     if symbols.is_empty() {
-        Expression::Number(res)
+        Ok(Expression::Number(res))
+    } else if symbols.len() == 1 && res == Number::from(0) {
+        Ok(symbols.remove(0))
     } else {
-        let mut v = vec![Expression::Symbol("+".to_string())];
-        if res != 0.0 {
+        let mut v = vec![Expression::Symbol(symbols::ADD)];
+        if res != Number::from(0) {
             v.push(Expression::Number(res))
         }
         v.append(&mut symbols);
-        Expression::List(v)
+        Ok(Expression::list(v))
     }
 }
 
-fn reduce_subtraction(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    let mut vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
+fn reduce_subtraction(mut vexp: Vec<Expression>) -> Result<Expression, LispError> {
     if vexp.len() <= 2 {
-        if let Expression::Number(n) = vexp[1] {
-            Expression::Number(-n)
+        if let Expression::Number(n) = &vexp[1] {
+            Ok(Expression::Number(n.neg()))
         } else {
-            Expression::List(vexp)
+            Ok(Expression::list(vexp.to_vec()))
         }
     } else {
-        let (numbers, mut symbols): (Vec<_>, Vec<_>) = vexp
-            .drain(2..)
-            .partition(|e| matches!(e, Expression::Number(_)));
+        let (numbers, mut symbols) = partition_numbers(vexp.drain(2..).collect());
 
-        let res: f64 = numbers.iter().fold(0.0, |mut acc, e| {
-            if let Expression::Number(n) = e {
-                acc += n;
-            }
-            acc
-        });
+        let res = numbers.iter().fold(Number::from(0), |acc, n| acc.add(n));
 
         if symbols.is_empty() {
-            if let Expression::Number(n) = vexp[1] {
-                Expression::Number(n - res)
+            if let Expression::Number(n) = &vexp[1] {
+                Ok(Expression::Number(n.sub(&res)))
             } else {
-                Expression::List(vec![
-                    Expression::Symbol("-".to_string()),
+                Ok(Expression::list(vec![
+                    Expression::Symbol(symbols::SUBTRACT),
                     vexp[1].clone(),
                     Expression::Number(res),
-                ])
+                ]))
+            }
+        } else if let Expression::Number(n) = &vexp[1] {
+            let res = n.sub(&res);
+            let mut v = vec![Expression::Symbol(symbols::SUBTRACT)];
+            if res != Number::from(0) {
+                v.push(Expression::Number(res))
             }
+            v.append(&mut symbols);
+            Ok(Expression::list(v))
         } else {
-            if let Expression::Number(n) = vexp[1] {
-                let res = n - res;
-                let mut v = vec![Expression::Symbol("-".to_string())];
-                if res != 0.0 {
-                    v.push(Expression::Number(res))
-                }
-                v.append(&mut symbols);
-                Expression::List(v)
-            } else {
-                let mut v = vec![Expression::Symbol("-".to_string()), vexp[1].clone()];
-                v.append(&mut symbols);
-                if res != 0.0 {
-                    v.push(Expression::Number(res))
-                }
-                Expression::List(v)
+            let mut v = vec![Expression::Symbol(symbols::SUBTRACT), vexp[1].clone()];
+            v.append(&mut symbols);
+            if res != Number::from(0) {
+                v.push(Expression::Number(res))
             }
+            Ok(Expression::list(v))
         }
     }
 }
 
-fn reduce_multiplication(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    let mut vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
-    let (numbers, mut symbols): (Vec<_>, Vec<_>) = vexp
-        .drain(1..)
-        .partition(|e| matches!(e, Expression::Number(_)));
+fn reduce_multiplication(mut vexp: Vec<Expression>) -> Result<Expression, LispError> {
+    let (numbers, mut symbols) =
+        partition_numbers(flatten(symbols::MULTIPLY, vexp.drain(1..).collect()));
 
-    let res: f64 = numbers.iter().fold(1.0, |mut acc, e| {
-        if let Expression::Number(n) = e {
-            acc *= n;
-        }
-        acc
-    });
+    let res = numbers.iter().fold(Number::from(1), |acc, n| acc.mul(n));
 
     if symbols.is_empty() {
-        Expression::Number(res)
+        Ok(Expression::Number(res))
+    } else if symbols.len() == 1 && res == Number::from(1) {
+        Ok(symbols.remove(0))
     } else {
-        let mut v = vec![Expression::Symbol("*".to_string())];
-        if res != 1.0 {
+        let mut v = vec![Expression::Symbol(symbols::MULTIPLY)];
+        if res != Number::from(1) {
             v.push(Expression::Number(res))
         }
         v.append(&mut symbols);
-        Expression::List(v)
+        Ok(Expression::list(v))
     }
 }
 
-fn reduce_division(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    let mut vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
+/*
+ * Dividing by an exact zero is an error, even when other divisors are still symbolic.
+ * Only an inexact dividend makes it an inexact division, which follows IEEE and may produce infinities or NaN.
+ */
+fn reduce_division(mut vexp: Vec<Expression>) -> Result<Expression, LispError> {
     if vexp.len() <= 2 {
-        if let Expression::Number(n) = vexp[1] {
-            Expression::Number(1.0 / n)
+        if let Expression::Number(n) = &vexp[1] {
+            match Number::from(1).div(n) {
+                Some(res) => Ok(Expression::Number(res)),
+                None => Err(LispError::DivisionByZero(Expression::list(vexp.to_vec()))),
+            }
         } else {
-            Expression::List(vexp)
+            Ok(Expression::list(vexp.to_vec()))
         }
     } else {
-        let (numbers, mut symbols): (Vec<_>, Vec<_>) = vexp
-            .drain(2..)
-            .partition(|e| matches!(e, Expression::Number(_)));
+        let call = Expression::list(vexp.to_vec());
+        let (numbers, mut symbols) = partition_numbers(vexp.drain(2..).collect());
 
-        let res: f64 = numbers.iter().fold(1.0, |mut acc, e| {
-            if let Expression::Number(n) = e {
-                acc *= n;
-            }
-            acc
-        });
+        let res = numbers.iter().fold(Number::from(1), |acc, n| acc.mul(n));
+
+        let inexact_dividend = matches!(&vexp[1], Expression::Number(n) if !n.is_exact());
+        if res.is_exact() && res.is_zero() && !inexact_dividend {
+            return Err(LispError::DivisionByZero(call));
+        }
 
         if symbols.is_empty() {
-            if let Expression::Number(n) = vexp[1] {
-                Expression::Number(n / res)
+            if let Expression::Number(n) = &vexp[1] {
+                Ok(Expression::Number(n.div(&res).unwrap()))
             } else {
-                Expression::List(vec![
-                    Expression::Symbol("/".to_string()),
+                Ok(Expression::list(vec![
+                    Expression::Symbol(symbols::DIVIDE),
                     vexp[1].clone(),
                     Expression::Number(res),
-                ])
+                ]))
             }
+        } else if let Expression::Number(n) = &vexp[1] {
+            let res = n.div(&res).unwrap();
+            let mut v = vec![Expression::Symbol(symbols::DIVIDE)];
+            if res != Number::from(1) {
+                v.push(Expression::Number(res));
+            }
+            v.append(&mut symbols);
+            Ok(Expression::list(v))
         } else {
-            if let Expression::Number(n) = vexp[1] {
-                let res = n / res;
-                let mut v = vec![Expression::Symbol("/".to_string())];
-                if res != 1.0 {
-                    v.push(Expression::Number(res));
-                }
-                v.append(&mut symbols);
-                Expression::List(v)
-            } else {
-                let mut v = vec![Expression::Symbol("/".to_string()), vexp[1].clone()];
-                v.append(&mut symbols);
-                if res != 1.0 {
-                    v.push(Expression::Number(res));
-                }
-                Expression::List(v)
+            let mut v = vec![Expression::Symbol(symbols::DIVIDE), vexp[1].clone()];
+            v.append(&mut symbols);
+            if res != Number::from(1) {
+                v.push(Expression::Number(res));
             }
+            Ok(Expression::list(v))
+        }
+    }
+}
+
+/*
+ * Reduces a call to one of the math builtins.
+ * Computes the result when every argument is numeric, otherwise returns the residual call,
+ * unless the arguments are wrong, see `check_numeric`.
+ * `min` and `max` fold their numeric arguments even when some are symbolic.
+ */
+fn reduce_math(mut vexp: Vec<Expression>, eval: &Evaluator) -> Result<Expression, LispError> {
+    let name = match &vexp[0] {
+        Expression::Symbol(s) => s.name(),
+        _ => return Ok(Expression::list(vexp.to_vec())),
+    };
+    check_numeric(&vexp, math::arity(name), eval)?;
+    let call = Expression::list(vexp.to_vec());
+    let (numbers, mut symbols) = partition_numbers(vexp.drain(1..).collect());
+
+    if symbols.is_empty() {
+        if math::divides_by_zero(name, &numbers) {
+            return Err(LispError::DivisionByZero(call));
         }
+        reserve(eval, math::allocates(name, &numbers), &call)?;
+        // With the arity and types checked, only integer division can be undefined
+        return match math::call(name, &numbers) {
+            Some(res) => Ok(Expression::Number(res)),
+            None if eval.deferred > 0 || eval.specializer.is_some() => Ok(call),
+            None => Err(LispError::Type(call, "integers".to_string())),
+        };
+    } else if (name == "min" || name == "max") && numbers.len() > 1 {
+        let wanted = if name == "min" {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        vexp.push(Expression::Number(math::extreme(&numbers, wanted)));
+        vexp.append(&mut symbols);
+        return Ok(Expression::list(vexp.to_vec()));
+    }
+    Ok(call)
+}
+
+/* Reduces a call to one of the string builtins, which only runs once every argument is known. */
+fn reduce_string(vexp: Vec<Expression>, eval: &Evaluator) -> Result<Expression, LispError> {
+    if !vexp[1..].iter().all(specializer::is_static) {
+        return Ok(Expression::list(vexp.to_vec()));
+    }
+    let name = match &vexp[0] {
+        Expression::Symbol(s) => s.name(),
+        _ => return Ok(Expression::list(vexp.to_vec())),
+    };
+    let call = Expression::list(vexp.to_vec());
+    reserve(eval, strings::allocates(name, &vexp[1..]), &call)?;
+    strings::call(name, &vexp[1..], &call)
+}
+
+/*
+ * Reduces a type predicate, `type-of` or `symbolic?`.
+ * The type of a value with free variables isn't known yet, except that `symbolic?` is true for it
+ * once the code runs for real, rather than being reduced ahead of time.
+ */
+fn reduce_predicate(vexp: Vec<Expression>, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    if vexp.len() != 2 {
+        return Err(LispError::Arity(
+            Expression::list(vexp.to_vec()),
+            "1".to_string(),
+        ));
+    }
+    let Expression::Symbol(name) = &vexp[0] else {
+        return Ok(Expression::list(vexp.to_vec()));
+    };
+    let name = name.name();
+    let value = &vexp[1];
+    // The builtins aren't bound to anything but are functions all the same
+    let builtin =
+        matches!(value, Expression::Symbol(s) if is_builtin(*s, eval) && !eval.env.is_defined(*s));
+    if !builtin && !specializer::is_static(value) {
+        if name == "symbolic?" && eval.deferred == 0 && eval.specializer.is_none() {
+            return Ok(Expression::Boolean(true));
+        }
+        return Ok(Expression::list(vexp.to_vec()));
+    }
+    let is = |holds: bool| Ok(Expression::Boolean(holds));
+    match name {
+        "type-of" => {
+            let type_name = if builtin {
+                "procedure".to_string()
+            } else {
+                value.type_name()
+            };
+            Ok(Expression::quote(Expression::Symbol(Symbol::new(
+                &type_name,
+            ))))
+        }
+        "symbolic?" => is(false),
+        "procedure?" => {
+            is(builtin || matches!(value, Expression::Lambda(..) | Expression::Procedure(_)))
+        }
+        "integer?" => is(matches!(value, Expression::Number(n) if n.is_integral())),
+        // Quoted list literals like `'(1 2)` are lists too
+        "list?" => is(value.elements().is_some()),
+        "pair?" => is(value
+            .elements()
+            .is_some_and(|elements| !elements.is_empty())),
+        // The others check the name of the type, like `vector?`
+        _ => is(!builtin && value.type_name() == name.trim_end_matches('?')),
+    }
+}
+
+/* Defines the constructor, predicate, accessors and modifiers of a record type. */
+fn evaluate_define_record(
+    vexp: &[Expression],
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let Some(procedures) = records::define(vexp) else {
+        return Err(LispError::Type(
+            Expression::list(vexp.to_vec()),
+            "(define-record-type name (constructor field...) predicate (field accessor [modifier])...)"
+                .to_string(),
+        ));
+    };
+    for (name, procedure) in procedures {
+        eval.env.global_push(name, procedure);
+    }
+    Ok(Expression::Nil)
+}
+
+/*
+ * Reduces a call to one of the vector or hash map builtins.
+ * Making, reading and changing them waits until the code actually runs, so it doesn't happen
+ * while a function body or an undecided branch is reduced ahead of time.
+ */
+fn reduce_collection(vexp: Vec<Expression>, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let name = match &vexp[0] {
+        Expression::Symbol(s) => s.name(),
+        _ => return Ok(Expression::list(vexp.to_vec())),
+    };
+    let call = Expression::list(vexp.to_vec());
+    let is_vector = vectors::FUNCTIONS.contains(&name);
+    let (min, max) = if is_vector {
+        vectors::arity(name)
+    } else {
+        maps::arity(name)
+    };
+    check_arity(min, max, &vexp[1..], || call.clone())?;
+    // Lengths and types never change, so they can be known ahead of time
+    let fixed = name == "vector-length";
+    let ahead = (eval.deferred > 0 || eval.specializer.is_some()) && !fixed;
+    if ahead || !vexp[1..].iter().all(specializer::is_static) {
+        return Ok(call);
+    }
+    match name {
+        "vector-map" => vector_map(&vexp[1], &vexp[2..], &call, eval),
+        "hash-for-each" => hash_for_each(&vexp[1], &vexp[2], &call, eval),
+        _ if is_vector => {
+            reserve(eval, vectors::allocates(name, &vexp[1..]), &call)?;
+            vectors::call(name, &vexp[1..], &call)
+        }
+        _ => maps::call(name, &vexp[1..], &call),
+    }
+}
+
+/*
+ * Calls a procedure of a record type, `vexp` is the procedure and its evaluated arguments.
+ * Like the collection builtins, only the predicate is known before the code actually runs.
+ */
+pub fn reduce_record(vexp: Vec<Expression>, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let Expression::Procedure(procedure) = &vexp[0] else {
+        return Ok(Expression::list(vexp));
+    };
+    let call = Expression::list(vexp.clone());
+    let arity = procedure.arity();
+    check_arity(arity, Some(arity), &vexp[1..], || call.clone())?;
+    let fixed = procedure.access == Access::Is;
+    let ahead = (eval.deferred > 0 || eval.specializer.is_some()) && !fixed;
+    if ahead || !vexp[1..].iter().all(specializer::is_static) {
+        return Ok(call);
     }
+    records::call(procedure, &vexp[1..], &call)
 }
 
-fn reduce_equality(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    let mut vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
+/* Runs `(gc)` or `(gc-stats)`, only once the code actually runs like the collection builtins. */
+fn reduce_memory(vexp: Vec<Expression>, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let call = Expression::list(vexp.to_vec());
+    if vexp.len() != 1 {
+        return Err(LispError::Arity(call, "0".to_string()));
+    }
+    let Expression::Symbol(name) = &vexp[0] else {
+        return Ok(call);
+    };
+    if eval.deferred > 0 || eval.specializer.is_some() {
+        return Ok(call);
+    }
+    Ok(gc::call(name.name()))
+}
+
+/*
+ * Reduces `error`, `raise` or a condition accessor. Nothing is raised while reducing ahead of time,
+ * only once the code runs, even if what is raised has free variables.
+ */
+fn reduce_condition(vexp: Vec<Expression>, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let call = Expression::list(vexp.to_vec());
+    let Expression::Symbol(name) = &vexp[0] else {
+        return Ok(call);
+    };
+    let name = name.name();
+    let (min, max) = conditions::arity(name);
+    check_arity(min, max, &vexp[1..], || call.clone())?;
+    let raises = name == "error" || name == "raise";
+    let ahead = eval.deferred > 0 || eval.specializer.is_some();
+    if ahead || (!raises && !specializer::is_static(&vexp[1])) {
+        return Ok(call);
+    }
+    conditions::call(name, &vexp[1..], &call)
+}
+
+/* Calls a function value from a builtin with already evaluated arguments. */
+fn call_function(
+    function: &Expression,
+    args: Vec<Expression>,
+    call: &Expression,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let mut inner = vec![function.clone()];
+    inner.extend(args.iter().cloned());
+    match function {
+        Expression::Lambda(params, body, scope) => invoke(&inner, params, body, scope, args, eval),
+        Expression::Procedure(_) => reduce_record(inner, eval),
+        _ => Err(LispError::Type(call.clone(), "a function".to_string())),
+    }
+}
+
+/* Calls a function on the elements of the vectors at each index, up to the length of the shortest one. */
+fn vector_map(
+    function: &Expression,
+    args: &[Expression],
+    call: &Expression,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let vectors: Vec<_> = args
+        .iter()
+        .map(|v| vectors::vector(v, call))
+        .collect::<Result<_, _>>()?;
+    let len = vectors.iter().map(|v| v.borrow().len()).min().unwrap_or(0);
+    let mut results = Vec::with_capacity(len);
+    for i in 0..len {
+        let elements = vectors.iter().map(|v| v.borrow()[i].clone()).collect();
+        results.push(call_function(function, elements, call, eval)?);
+    }
+    Ok(vectors::new(results))
+}
+
+/* Calls a function with the key and value of every entry, the entries are copied first so it may change the map. */
+fn hash_for_each(
+    m: &Expression,
+    function: &Expression,
+    call: &Expression,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let entries = maps::map(m, call)?.borrow().entries().to_vec();
+    for (k, v) in entries {
+        call_function(function, vec![k, v], call, eval)?;
+    }
+    Ok(Expression::Nil)
+}
+
+/* Numbers compare by value regardless of exactness, everything else structurally. */
+fn is_equal(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Number(x), Expression::Number(y)) => x.num_eq(y),
+        _ => a == b,
+    }
+}
+
+fn reduce_equality(mut vexp: Vec<Expression>) -> Result<Expression, LispError> {
     if vexp.len() <= 1 {
-        return Expression::List(vexp);
+        return Ok(Expression::list(vexp.to_vec()));
     }
     if vexp.len() == 2 {
-        return Expression::Boolean(true);
+        return Ok(Expression::Boolean(true));
     }
+    // Symbols and residual calls can't be compared yet
     let (symbols, literals): (Vec<_>, Vec<_>) = vexp
         .drain(1..)
-        .partition(|e| matches!(e, Expression::Symbol(_)));
+        .partition(|e| matches!(e, Expression::Symbol(_) | Expression::List(_)));
 
-    let is_match = literals.is_empty() || literals.iter().all(|e| e == &literals[0]);
+    let is_match = literals.is_empty() || literals.iter().all(|e| is_equal(e, &literals[0]));
     if symbols.is_empty() || !is_match {
-        Expression::Boolean(is_match)
+        Ok(Expression::Boolean(is_match))
     } else {
-        let mut ret = vec![Expression::Symbol("=".to_string())];
+        let mut ret = vec![Expression::Symbol(symbols::EQUAL)];
         if !literals.is_empty() {
             ret.push(literals[0].clone());
         }
-        let mut seen = HashSet::new();
-        let mut count = 0;
+        let mut seen: Vec<&Expression> = Vec::new();
         for e in symbols.iter() {
-            if let Expression::Symbol(s) = e {
-                if !seen.contains(s) {
-                    seen.insert(s);
-                    count += 1;
-                    ret.push(Expression::Symbol(s.clone()))
-                }
+            if !seen.contains(&e) {
+                seen.push(e);
+                ret.push(e.clone())
             }
         }
         // If there's only one symbol it's obviously equal to itself!
-        if count == 1 && literals.len() == 0 {
-            return Expression::Boolean(true);
+        if seen.len() == 1 && literals.is_empty() {
+            return Ok(Expression::Boolean(true));
         }
-        Expression::List(ret)
+        Ok(Expression::list(ret))
     }
 }
 
-fn evaluate_define(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    if vexp.len() <= 2 {
-        return Expression::List(vexp);
-    } else {
-        if let Expression::Symbol(s) = vexp[1].clone() {
-            let result = evaluate_expression(&vexp[2], eval);
-            eval.env.global_push(s, Vec::new(), result.clone());
-            return result;
-        } else if let Expression::List(v) = vexp[1].clone() {
-            let params = v.iter().try_fold(
-                Vec::with_capacity(v.len()),
-                |mut list: Vec<String>, e| match e {
-                    Expression::Symbol(s) => {
-                        list.push(s.clone());
-                        Ok(list)
-                    }
-                    _ => Err(()),
-                },
-            );
-
-            match params {
-                Ok(list) => {
-                    let result = evaluate_expression(&vexp[2], eval);
-                    eval.env
-                        .global_push(list[0].clone(), list[1..].to_vec(), result.clone());
-                    return result;
+/*
+ * Parses a parameter list like `(a b #:optional c (d 1) #:key (e 2) . rest)`, or a single symbol taking every argument.
+ * An optional or keyword parameter without a default value defaults to nil.
+ * Returns None if the list is malformed.
+ */
+pub fn parse_params(e: &Expression) -> Option<Params> {
+    let v = match e {
+        Expression::List(v) => v,
+        Expression::Symbol(s) => {
+            return Some(Params {
+                rest: Some(*s),
+                ..Params::default()
+            });
+        }
+        Expression::Nil => return Some(Params::default()),
+        _ => return None,
+    };
+    let mut params = Params::default();
+    let mut marker = None;
+    let mut iter = v.iter();
+    while let Some(e) = iter.next() {
+        let (name, default) = match e {
+            Expression::Symbol(symbols::OPTIONAL) if marker.is_none() => {
+                marker = Some(symbols::OPTIONAL);
+                continue;
+            }
+            Expression::Symbol(symbols::KEY) if marker != Some(symbols::KEY) => {
+                marker = Some(symbols::KEY);
+                continue;
+            }
+            Expression::Symbol(symbols::DOT) => {
+                match iter.next() {
+                    Some(Expression::Symbol(rest)) => params.rest = Some(*rest),
+                    _ => return None,
                 }
-                Err(_) => Expression::List(vexp),
+                // The rest parameter has to be the last one
+                return iter.next().is_none().then_some(params);
+            }
+            Expression::Symbol(s) if marker.is_some() => (*s, Expression::Nil),
+            Expression::Symbol(s) => {
+                params.required.push(*s);
+                continue;
             }
+            Expression::List(l) if marker.is_some() && l.len() == 2 => match &l[0] {
+                Expression::Symbol(s) => (*s, l[1].clone()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if marker == Some(symbols::KEY) {
+            params.key.push((name, default));
         } else {
-            Expression::List(vexp)
+            params.optional.push((name, default));
         }
     }
+    Some(params)
 }
 
-fn apply(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    if let Expression::Symbol(s) = &vexp[0] {
-        eval.env.add_scope(); // Add a scope for the current evaluation!
-        if eval.env.is_defined(&s) {
-            let (params, e) = eval.env.get(&s);
-            let mappings = params.iter().zip(&vexp[1..]);
-            for (param_name, arg_val) in mappings {
-                let arg_val = evaluate_expression(arg_val, eval);
-                eval.env.local_push(param_name.clone(), Vec::new(), arg_val);
+/*
+ * Builds a function value, reducing the body as far as possible right away.
+ * The parameters stay symbolic while the body is reduced, so they can't be captured by other bindings.
+ */
+fn make_lambda(
+    mut params: Params,
+    body: &Expression,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    eval.env.add_scope();
+    for param in params.names() {
+        eval.env.local_push(*param, Expression::Symbol(*param));
+    }
+    let defaults: Result<Vec<_>, _> = params
+        .optional
+        .iter()
+        .chain(params.key.iter())
+        .map(|(_, default)| evaluate_expression(default, eval))
+        .collect();
+    eval.deferred += 1;
+    let body = evaluate_expression(body, eval).map(|body| resolve(&body, &eval.env, &[]));
+    eval.deferred -= 1;
+    eval.env.pop_scope();
+    let slots = params.optional.iter_mut().chain(params.key.iter_mut());
+    for ((_, default), value) in slots.zip(defaults?) {
+        *default = value;
+    }
+    Ok(Expression::Lambda(
+        Rc::new(params),
+        Rc::new(body?),
+        eval.env.capture(),
+    ))
+}
+
+/*
+ * Replaces the variables of the scopes being reduced by their lexical address, so a call finds
+ * them without searching its scopes by name. Those are all the local variables a reduced body
+ * still refers to, the others were replaced by their values, while globals are still found by name.
+ * `inner` are the variables of the guard handlers `e` is in, each in a scope of its own.
+ * Special forms other than cond and guard are left as written.
+ */
+fn resolve(e: &Expression, env: &Environment, inner: &[Symbol]) -> Expression {
+    match e {
+        Expression::Symbol(s) if inner.contains(s) => e.clone(),
+        Expression::Symbol(s) => match env.address(*s) {
+            Some((depth, index)) => Expression::Local(*s, depth + inner.len(), index),
+            None => e.clone(),
+        },
+        Expression::List(v) => match v.first() {
+            Some(Expression::Symbol(s)) if *s == symbols::GUARD => match parse_guard(v) {
+                Some((variable, clauses)) => {
+                    let handler = [inner, &[variable]].concat();
+                    let mut spec = vec![Expression::Symbol(variable)];
+                    for clause in clauses.iter().filter_map(Expression::elements) {
+                        let clause = clause.iter().map(|e| resolve(e, env, &handler));
+                        spec.push(Expression::list(clause.collect()));
+                    }
+                    let mut guard = vec![v[0].clone(), Expression::list(spec)];
+                    guard.extend(v[2..].iter().map(|e| resolve(e, env, inner)));
+                    Expression::list(guard)
+                }
+                None => e.clone(),
+            },
+            Some(Expression::Symbol(s)) if *s != symbols::COND && SPECIAL_FORMS.contains(s) => {
+                e.clone()
             }
-            let ret = evaluate_expression(&e, eval);
-            eval.env.pop_scope();
-            ret
-        } else {
-            eval.env.pop_scope();
-            Expression::List(vexp)
+            _ => Expression::list(v.iter().map(|e| resolve(e, env, inner)).collect()),
+        },
+        _ => e.clone(),
+    }
+}
+
+fn evaluate_lambda(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    if vexp.len() == 3
+        && let Some(params) = parse_params(&vexp[1])
+    {
+        make_lambda(params, &vexp[2], eval)
+    } else {
+        Ok(Expression::list(vexp.to_vec()))
+    }
+}
+
+fn evaluate_define(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    if vexp.len() <= 2 {
+        Ok(Expression::list(vexp.to_vec()))
+    } else if let Expression::Symbol(s) = vexp[1].clone() {
+        let result = evaluate_expression(&vexp[2], eval)?;
+        eval.env.global_push(s, result.clone());
+        Ok(result)
+    } else if let Expression::List(v) = &vexp[1]
+        && let Some(Expression::Symbol(name)) = v.first()
+        && let Some(params) = parse_params(&Expression::list(v[1..].to_vec()))
+    {
+        // The function may refer to itself before it is defined
+        let declared = eval.symbolic.insert(*name);
+        let result = make_lambda(params, &vexp[2], eval);
+        if declared {
+            eval.symbolic.remove(name);
+        }
+        let result = result?;
+        eval.env.global_push(*name, result.clone());
+        Ok(result)
+    } else {
+        Ok(Expression::list(vexp.to_vec()))
+    }
+}
+
+// Keyword arguments of a call, by parameter name
+type KeywordArgs = Vec<(Symbol, Expression)>;
+
+/*
+ * Takes the `:name value` pairs for keyword parameters out of the arguments, leaving the positional ones.
+ * A keyword given twice takes the last value.
+ */
+fn split_keywords(
+    call: &[Expression],
+    params: &Params,
+    args: Vec<Expression>,
+) -> Result<(Vec<Expression>, KeywordArgs), LispError> {
+    if params.key.is_empty() {
+        return Ok((args, Vec::new()));
+    }
+    let mut positional = Vec::new();
+    let mut keys = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Expression::Keyword(k) = arg else {
+            positional.push(arg);
+            continue;
+        };
+        let Some((name, _)) = params.key.iter().find(|(name, _)| name.name() == k) else {
+            let names: Vec<String> = params
+                .key
+                .iter()
+                .map(|(name, _)| format!(":{}", name))
+                .collect();
+            return Err(LispError::Type(
+                Expression::list(call.to_vec()),
+                format!("one of the keywords {}", names.join(" ")),
+            ));
+        };
+        match args.next() {
+            Some(value) => keys.push((*name, value)),
+            None => {
+                return Err(LispError::Arity(
+                    Expression::list(call.to_vec()),
+                    format!("a value after :{}", k),
+                ));
+            }
+        }
+    }
+    Ok((positional, keys))
+}
+
+/* Binds the evaluated arguments to the parameters in the current scope, the arity must already be checked. */
+fn bind_params(
+    params: &Params,
+    args: Vec<Expression>,
+    keys: KeywordArgs,
+    eval: &mut Evaluator,
+) -> Result<(), LispError> {
+    let mut args = args.into_iter();
+    for name in params.required.iter() {
+        let arg = args.next().unwrap();
+        eval.env.local_push(*name, arg);
+    }
+    for (name, default) in params.optional.iter() {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => evaluate_expression(default, eval)?,
+        };
+        eval.env.local_push(*name, arg);
+    }
+    for (name, default) in params.key.iter() {
+        let arg = match keys.iter().rev().find(|(k, _)| k == name) {
+            Some((_, arg)) => arg.clone(),
+            None => evaluate_expression(default, eval)?,
+        };
+        eval.env.local_push(*name, arg);
+    }
+    if let Some(name) = &params.rest {
+        // Variadic arguments are collected into a list
+        eval.env
+            .local_push(*name, Expression::list_or_nil(args.collect()));
+    }
+    Ok(())
+}
+
+/*
+ * Specialises a function on its first arguments.
+ * Returns a function of the remaining parameters whose body is reduced with the supplied arguments.
+ */
+fn curry(
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    args: Vec<Expression>,
+    keys: KeywordArgs,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let remaining = Params {
+        required: params.required[args.len()..].to_vec(),
+        key: params
+            .key
+            .iter()
+            .filter(|(name, _)| !keys.iter().any(|(k, _)| k == name))
+            .cloned()
+            .collect(),
+        ..params.clone()
+    };
+    let outer = eval.env.enter(scope.clone());
+    for (name, arg) in params.required.iter().cloned().zip(args).chain(keys) {
+        eval.env.local_push(name, arg);
+    }
+    let ret = make_lambda(remaining, body, eval);
+    eval.env.restore(outer);
+    ret
+}
+
+/* In curry mode, the function an under-applied call makes instead, for the virtual machine. */
+pub fn curried(
+    call: &[Expression],
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    eval: &mut Evaluator,
+) -> Result<Option<Expression>, LispError> {
+    if !eval.curry {
+        return Ok(None);
+    }
+    let (args, keys) = split_keywords(call, params, call[1..].to_vec())?;
+    if params.accepts(args.len()) || args.len() >= params.required.len() {
+        return Ok(None);
+    }
+    curry(params, body, scope, args, keys, eval).map(Some)
+}
+
+/* Calls a function with evaluated arguments, its body runs in a new frame inside the scope the function was made in. */
+fn invoke(
+    call: &[Expression],
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    args: Vec<Expression>,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let (args, keys) = split_keywords(call, params, args)?;
+    if !params.accepts(args.len()) {
+        if eval.curry && args.len() < params.required.len() {
+            return curry(params, body, scope, args, keys, eval);
+        }
+        return Err(LispError::Arity(
+            Expression::list(call.to_vec()),
+            params.describe(),
+        ));
+    }
+    enter_call(call, eval)?;
+    let outer = eval.env.enter(scope.clone());
+    let ret = bind_params(params, args, keys, eval).and_then(|_| evaluate_expression(body, eval));
+    eval.env.restore(outer);
+    leave_call(eval);
+    ret
+}
+
+/* Starts the limits over for a new top level evaluation. */
+pub fn begin(eval: &mut Evaluator) {
+    eval.steps = 0;
+    eval.depth = 0;
+    eval.unfolded = 0;
+    eval.heap_base = heap::live();
+}
+
+/*
+ * Checks whether Ctrl-C was pressed, counts a step against the fuel and checks the memory in use,
+ * and the native stack, which recursion in the program uses up whatever the limits.
+ * `e` gives what was being evaluated for the errors.
+ */
+pub fn step(eval: &mut Evaluator, e: impl FnOnce() -> Expression) -> Result<(), LispError> {
+    if eval.interruptible && interrupt::interrupted() {
+        return Err(LispError::Interrupted);
+    }
+    if stack::exhausted() {
+        return Err(LispError::Limit(e(), "stack".to_string()));
+    }
+    eval.steps += 1;
+    if eval.fuel.is_some_and(|fuel| eval.steps > fuel) {
+        return Err(LispError::Limit(e(), "fuel".to_string()));
+    }
+    if let Some(max) = eval.max_heap
+        && heap::live().saturating_sub(eval.heap_base) > max
+    {
+        return Err(LispError::Limit(e(), "heap".to_string()));
+    }
+    Ok(())
+}
+
+/*
+ * Fails like the heap limit would once `bytes` more are in use, for builtins to check before they
+ * allocate something huge, which could take all the memory before the next step.
+ */
+fn reserve(eval: &Evaluator, bytes: usize, call: &Expression) -> Result<(), LispError> {
+    if let Some(max) = eval.max_heap
+        && heap::live()
+            .saturating_sub(eval.heap_base)
+            .saturating_add(bytes)
+            > max
+    {
+        return Err(LispError::Limit(call.clone(), "heap".to_string()));
+    }
+    Ok(())
+}
+
+/* Counts a call that is about to run, the caller leaves it with `leave_call`. */
+pub fn enter_call(call: &[Expression], eval: &mut Evaluator) -> Result<(), LispError> {
+    if eval.max_depth.is_some_and(|max| eval.depth >= max) {
+        return Err(LispError::Limit(
+            Expression::list(call.to_vec()),
+            "recursion depth".to_string(),
+        ));
+    }
+    eval.depth += 1;
+    Ok(())
+}
+
+pub fn leave_call(eval: &mut Evaluator) {
+    eval.depth -= 1;
+}
+
+/*
+ * Binds the arguments of a call in a new frame inside `scope`, which becomes the current one.
+ * Returns the scope to go back to with `restore` once the body has run, for the virtual machine.
+ */
+pub fn bind_call(
+    call: &[Expression],
+    params: &Params,
+    scope: &Scope,
+    args: Vec<Expression>,
+    eval: &mut Evaluator,
+) -> Result<Scope, LispError> {
+    let (args, keys) = split_keywords(call, params, args)?;
+    if !params.accepts(args.len()) {
+        return Err(LispError::Arity(
+            Expression::list(call.to_vec()),
+            params.describe(),
+        ));
+    }
+    let outer = eval.env.enter(scope.clone());
+    match bind_params(params, args, keys, eval) {
+        Ok(()) => Ok(outer),
+        Err(err) => {
+            eval.env.restore(outer);
+            Err(err)
+        }
+    }
+}
+
+fn call_lambda(
+    call: &[Expression],
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let args = evaluate_all(&call[1..], eval)?;
+    invoke(call, params, body, scope, args, eval)
+}
+
+/*
+ * Calls a global function, guarding against unfolding recursion on symbolic arguments forever.
+ * A call with symbolic arguments is left residual when the same function is already being unfolded
+ * with the same static arguments, or when the unfolding budget is used up. The budget counts every
+ * unfolding, not only nested ones, since a recursion that branches unfolds exponentially many.
+ */
+fn unfold_call(
+    name: Symbol,
+    call: &[Expression],
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let args = evaluate_all(&call[1..], eval)?;
+    if args.iter().all(specializer::is_static) {
+        return invoke(call, params, body, scope, args, eval);
+    }
+
+    let key = (
+        name,
+        args.iter()
+            .map(|arg| specializer::is_static(arg).then(|| arg.clone()))
+            .collect(),
+    );
+    if eval.unfolded >= eval.unfold_budget || eval.unfolding.contains(&key) {
+        let mut v = vec![Expression::Symbol(name)];
+        v.extend(args);
+        return Ok(Expression::list(v));
+    }
+    eval.unfolded += 1;
+    eval.unfolding.push(key);
+    let ret = invoke(call, params, body, scope, args, eval);
+    eval.unfolding.pop();
+    ret
+}
+
+/* Returns the variant of a function for the static arguments of the key, specialising its body if it is new. */
+fn specialize_variant(
+    name: Symbol,
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    key: Vec<Option<Expression>>,
+    eval: &mut Evaluator,
+) -> Result<(Symbol, Vec<Option<Expression>>), LispError> {
+    let spec = eval.specializer.as_mut().unwrap();
+    let (variant, key, fresh) = spec.variant(name, key, &eval.env);
+    if fresh {
+        let outer = eval.env.enter(scope.clone());
+        for (param, arg) in params.required.iter().zip(key.iter()) {
+            let arg = arg.clone().unwrap_or(Expression::Symbol(*param));
+            eval.env.local_push(*param, arg);
+        }
+        // The body of a variant starts out under static control
+        let dynamic = std::mem::take(&mut eval.specializer.as_mut().unwrap().dynamic);
+        let result = evaluate_expression(body, eval);
+        eval.specializer.as_mut().unwrap().dynamic = dynamic;
+        eval.env.restore(outer);
+
+        let dynamic_params = Params {
+            required: params
+                .required
+                .iter()
+                .zip(key.iter())
+                .filter(|(_, arg)| arg.is_none())
+                .map(|(param, _)| *param)
+                .collect(),
+            ..Params::default()
+        };
+        let definition = specializer::definition(variant, &dynamic_params, result?);
+        eval.specializer
+            .as_mut()
+            .unwrap()
+            .define(variant, definition);
+    }
+    Ok((variant, key))
+}
+
+/*
+ * Calls a global function while specialising.
+ * Under dynamic control, a call with dynamic arguments becomes a call to a variant specialised on the static ones.
+ * Calls nested too deeply are left to the original function, which then ends up in the residual program.
+ */
+fn specialize_call(
+    name: Symbol,
+    call: &[Expression],
+    params: &Params,
+    body: &Expression,
+    scope: &Scope,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let args = evaluate_all(&call[1..], eval)?;
+    let spec = eval.specializer.as_mut().unwrap();
+    let all_static = args.iter().all(specializer::is_static);
+    let only_required = params.only_required();
+    let residual = spec.dynamic > 0 && !all_static;
+
+    if spec.depth >= MAX_UNFOLD_DEPTH
+        || (residual && (!only_required || args.len() != params.required.len()))
+    {
+        let mut v = vec![Expression::Symbol(name)];
+        v.extend(args);
+        return Ok(Expression::list(v));
+    }
+    if residual {
+        let key = args
+            .iter()
+            .map(|arg| specializer::is_static(arg).then(|| arg.clone()))
+            .collect();
+        let (variant, key) = specialize_variant(name, params, body, scope, key, eval)?;
+        let mut v = vec![Expression::Symbol(variant)];
+        v.extend(
+            args.into_iter()
+                .zip(key)
+                .filter(|(_, arg)| arg.is_none())
+                .map(|(arg, _)| arg),
+        );
+        return Ok(Expression::list(v));
+    }
+
+    spec.depth += 1;
+    let ret = invoke(call, params, body, scope, args, eval);
+    eval.specializer.as_mut().unwrap().depth -= 1;
+    ret
+}
+
+/*
+ * Specialises a global function on the static arguments, the others stay parameters.
+ * Returns the residual program, starting with the definition of the specialised function.
+ */
+pub fn specialize(
+    name: Symbol,
+    args: Vec<Expression>,
+    eval: &mut Evaluator,
+) -> Result<Vec<Expression>, LispError> {
+    let mut call = vec![Expression::Symbol(name)];
+    call.extend(args.iter().cloned());
+    let (params, body, scope) = match eval.env.get(name) {
+        Expression::Lambda(params, body, scope) if params.only_required() => (params, body, scope),
+        _ => {
+            return Err(LispError::Type(
+                Expression::list(call),
+                "a function with only required parameters".to_string(),
+            ));
         }
+    };
+    if args.len() != params.required.len() {
+        return Err(LispError::Arity(Expression::list(call), params.describe()));
+    }
+
+    let key = args
+        .iter()
+        .map(|arg| specializer::is_static(arg).then(|| arg.clone()))
+        .collect();
+    let outer = eval.specializer.replace(Specializer::new());
+    let result = specialize_variant(name, &params, &body, &scope, key, eval);
+    let spec = std::mem::replace(&mut eval.specializer, outer).unwrap();
+    result?;
+    Ok(spec.program(&eval.env))
+}
+
+fn evaluate_specialize(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    match vexp.get(1) {
+        Some(Expression::Symbol(name)) => {
+            let args = evaluate_all(&vexp[2..], eval)?;
+            Ok(Expression::list(specialize(*name, args, eval)?))
+        }
+        _ => Err(LispError::Type(
+            Expression::list(vexp.to_vec()),
+            "a function name".to_string(),
+        )),
+    }
+}
+
+fn apply(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let head = match &vexp[0] {
+        Expression::Symbol(s) => match eval.env.find(*s) {
+            Some(value) => {
+                step(eval, || vexp[0].clone())?;
+                value
+            }
+            None => {
+                check_symbolic(*s, eval).map_err(|err| locate(err, vexp))?;
+                vexp[0].clone()
+            }
+        },
+        e => evaluate_expression(e, eval)?,
+    };
+    if let Expression::Lambda(params, body, scope) = head {
+        if eval.specializer.is_some()
+            && let Expression::Symbol(name) = &vexp[0]
+            && eval.env.get_local(*name).is_none()
+        {
+            let name = *name;
+            return specialize_call(name, vexp, &params, &body, &scope, eval);
+        }
+        if let Expression::Symbol(name) = &vexp[0]
+            && eval.env.get_local(*name).is_none()
+        {
+            let name = *name;
+            return unfold_call(name, vexp, &params, &body, &scope, eval);
+        }
+        call_lambda(vexp, &params, &body, &scope, eval)
+    } else if let Expression::Procedure(_) = head {
+        let mut v = vec![head];
+        v.append(&mut evaluate_all(&vexp[1..], eval)?);
+        reduce_record(v, eval)
     } else {
-        Expression::List(vexp)
+        // Not a function, so this is either a residual call or plain data
+        let mut v = vec![head];
+        v.append(&mut evaluate_all(&vexp[1..], eval)?);
+        Ok(Expression::list(v))
     }
 }
 
-fn evaluate_cond(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
+/*
+ * Replaces the symbols bound in local scopes with their values, without evaluating anything.
+ * Used for code that has to stay residual but may outlive the scope it was written in.
+ */
+fn substitute_locals(e: &Expression, shadowed: &[Symbol], eval: &Evaluator) -> Expression {
+    match e {
+        Expression::Symbol(s) if !shadowed.contains(s) => {
+            eval.env.get_local(*s).unwrap_or_else(|| e.clone())
+        }
+        Expression::Local(s, ..) if !shadowed.contains(s) => {
+            eval.env.get_local(*s).unwrap_or(Expression::Symbol(*s))
+        }
+        Expression::Local(s, ..) => Expression::Symbol(*s),
+        Expression::List(v) => {
+            // Parameters of a nested lambda shadow the outer bindings
+            if let [Expression::Symbol(symbols::LAMBDA), params, body] = v.as_slice()
+                && let Some(params) = parse_params(params)
+            {
+                let mut shadowed = shadowed.to_vec();
+                shadowed.extend(params.names().cloned());
+                let mut v = v.to_vec();
+                v[2] = substitute_locals(body, &shadowed, eval);
+                return Expression::list(v);
+            }
+            Expression::list(
+                v.iter()
+                    .map(|e| substitute_locals(e, shadowed, eval))
+                    .collect(),
+            )
+        }
+        _ => e.clone(),
+    }
+}
+
+/*
+ * Reduces code that only runs depending on an undecided test.
+ * Code that can't be reduced, for example because it would fail, is kept as it is.
+ */
+fn reduce_dynamic(e: &Expression, eval: &mut Evaluator) -> Result<Expression, LispError> {
+    if let Some(spec) = eval.specializer.as_mut() {
+        spec.dynamic += 1;
+    }
+    eval.deferred += 1;
+    let ret = evaluate_expression(e, eval);
+    eval.deferred -= 1;
+    if let Some(spec) = eval.specializer.as_mut() {
+        spec.dynamic -= 1;
+    }
+    match ret {
+        // A misspelt name is a mistake wherever it is, and a limit holds wherever it's reached
+        Err(err @ (LispError::Unbound(..) | LispError::Limit(..))) => Err(err),
+        // Ctrl-C stops the reduction, and what the program raises is for its guards
+        Err(err @ (LispError::Interrupted | LispError::Raised(_))) => Err(err),
+        Err(_) => Ok(substitute_locals(e, &[], eval)),
+        ok => ok,
+    }
+}
+
+/*
+ * Reduces the clauses that can be decided and keeps the rest as a residual cond.
+ * Clauses after one that is known to be true can never be reached and are dropped.
+ */
+fn evaluate_cond(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
     if vexp.len() < 2 {
-        return Expression::List(vexp);
+        Ok(Expression::list(vexp.to_vec()))
     } else {
         let mut unevaluated = Vec::new();
         for condition in vexp[1..].iter() {
             match condition {
                 Expression::List(l) => {
                     if l.len() == 2 {
-                        let check = evaluate_expression(&l[0], eval);
+                        let check = if unevaluated.is_empty() {
+                            evaluate_expression(&l[0], eval)?
+                        } else {
+                            reduce_dynamic(&l[0], eval)?
+                        };
                         if check == Expression::Boolean(true) {
-                            if unevaluated.len() == 0 {
+                            if unevaluated.is_empty() {
                                 return evaluate_expression(&l[1], eval);
                             } else {
-                                unevaluated.push(condition.clone());
+                                let branch = reduce_dynamic(&l[1], eval)?;
+                                unevaluated.push(Expression::list(vec![check, branch]));
+                                break;
                             }
                         } else if check != Expression::Boolean(false) {
-                            unevaluated.push(condition.clone());
+                            let branch = reduce_dynamic(&l[1], eval)?;
+                            unevaluated.push(Expression::list(vec![check, branch]));
                         }
                     } else {
-                        unevaluated.push(condition.clone());
+                        unevaluated.push(substitute_locals(condition, &[], eval));
                     }
                 }
-                _ => unevaluated.push(condition.clone()),
+                _ => unevaluated.push(substitute_locals(condition, &[], eval)),
             }
         }
-        if unevaluated.len() != 0 {
-            let mut v = vec![Expression::Symbol("cond".to_string())];
-            v.push(Expression::List(unevaluated));
-            Expression::List(v)
+        if !unevaluated.is_empty() {
+            let mut v = vec![Expression::Symbol(symbols::COND)];
+            v.append(&mut unevaluated);
+            Ok(Expression::list(v))
         } else {
-            Expression::Nil
+            Ok(Expression::Nil)
+        }
+    }
+}
+
+/*
+ * `(guard (variable clause...) body...)` evaluates the body, and if it raises, the clauses like a `cond`
+ * with the variable bound to what was raised, or a condition for an error of a builtin.
+ * A test that isn't false takes its clause, what no clause takes is raised again.
+ * While reducing ahead of time the guard stays as it is, errors are only caught once the code runs.
+ */
+fn evaluate_guard(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let call = Expression::list(vexp.to_vec());
+    let Some((variable, clauses)) = parse_guard(vexp) else {
+        return Err(LispError::Type(
+            call,
+            "(guard (variable clause...) body...)".to_string(),
+        ));
+    };
+    if eval.deferred > 0 || eval.specializer.is_some() {
+        return Ok(call);
+    }
+    let scope = eval.env.capture();
+    let err = match evaluate_body(&vexp[2..], eval) {
+        Ok(e) => return Ok(e),
+        Err(err) => err,
+    };
+    eval.env.restore(scope.clone());
+    let Some(condition) = conditions::from_error(&err) else {
+        return Err(err);
+    };
+    eval.env.add_scope();
+    eval.env.local_push(variable, condition);
+    let ret = evaluate_handler(clauses, eval);
+    eval.env.restore(scope);
+    match ret {
+        Ok(Some(e)) => Ok(e),
+        Ok(None) => Err(err),
+        Err(err) => Err(err),
+    }
+}
+
+/* The variable and clauses of a guard, each clause a test and an expression. */
+pub fn parse_guard(vexp: &[Expression]) -> Option<(Symbol, &[Expression])> {
+    let [_, Expression::List(spec), _, ..] = vexp else {
+        return None;
+    };
+    let (Some(Expression::Symbol(variable)), clauses) = (spec.first(), &spec[1..]) else {
+        return None;
+    };
+    let clause = |c: &Expression| matches!(c, Expression::List(l) if l.len() == 2);
+    clauses.iter().all(clause).then_some((*variable, clauses))
+}
+
+fn evaluate_body(body: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    let mut ret = Expression::Nil;
+    for e in body {
+        ret = evaluate_expression(e, eval)?;
+    }
+    Ok(ret)
+}
+
+/* The value of the first clause whose test isn't false, None if there is none. */
+fn evaluate_handler(
+    clauses: &[Expression],
+    eval: &mut Evaluator,
+) -> Result<Option<Expression>, LispError> {
+    for clause in clauses {
+        let Expression::List(clause) = clause else {
+            continue;
+        };
+        if evaluate_expression(&clause[0], eval)? != Expression::Boolean(false) {
+            return evaluate_expression(&clause[1], eval).map(Some);
         }
     }
+    Ok(None)
 }
 
-// fn evaluate_lambda(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
+pub fn is_builtin(name: Symbol, eval: &Evaluator) -> bool {
+    BUILTINS.contains(&name) || eval.builtins.contains_key(&name)
+}
 
-// }
+/* Checks that an unbound name may stay symbolic, which in strict mode it only may if it was declared. */
+pub fn check_symbolic(name: Symbol, eval: &Evaluator) -> Result<(), LispError> {
+    let builtin = is_builtin(name, eval) || SPECIAL_FORMS.contains(&name);
+    if eval.strict && !builtin && !eval.symbolic.contains(&name) {
+        Err(LispError::Unbound(name.to_string(), Expression::Nil))
+    } else {
+        Ok(())
+    }
+}
 
-fn evaluate_list(vexp: Vec<Expression>, eval: &mut Evaluator) -> Expression {
-    if let Some(Expression::Symbol(s)) = vexp.get(0) {
-        match s.as_str() {
-            "+" => reduce_addition(vexp, eval),
-            "-" => reduce_subtraction(vexp, eval),
-            "*" => reduce_multiplication(vexp, eval),
-            "/" => reduce_division(vexp, eval),
-            "=" => reduce_equality(vexp, eval),
-            "define" => evaluate_define(vexp, eval),
-            "cond" => evaluate_cond(vexp, eval),
-            // "lambda" => evaluate_lambda(vexp, eval),
+/* Fills in the expression an unbound name was found in, if it isn't known yet. */
+fn locate(err: LispError, e: &[Expression]) -> LispError {
+    match err {
+        LispError::Unbound(name, Expression::Nil) => {
+            LispError::Unbound(name, Expression::list(e.to_vec()))
+        }
+        err => err,
+    }
+}
+
+/* Declares free variables that may stay symbolic, this turns on strict mode. */
+fn evaluate_declare(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    for e in vexp[1..].iter() {
+        match e {
+            Expression::Symbol(s) => {
+                eval.symbolic.insert(*s);
+            }
+            _ => {
+                return Err(LispError::Type(
+                    Expression::list(vexp.to_vec()),
+                    "a symbol".to_string(),
+                ));
+            }
+        }
+    }
+    eval.strict = true;
+    Ok(Expression::Nil)
+}
+
+/* Applies the builtin `name` to a call whose arguments are already evaluated, the operator is its name. */
+pub fn apply_builtin(
+    name: Symbol,
+    vexp: Vec<Expression>,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    let numeric = match name {
+        symbols::ADD | symbols::MULTIPLY => Some((0, None)),
+        symbols::SUBTRACT | symbols::DIVIDE => Some((1, None)),
+        _ => None,
+    };
+    if let Some(arity) = numeric {
+        check_numeric(&vexp, arity, eval)?;
+    }
+    match name {
+        symbols::ADD => reduce_addition(vexp),
+        symbols::SUBTRACT => reduce_subtraction(vexp),
+        symbols::MULTIPLY => reduce_multiplication(vexp),
+        symbols::DIVIDE => reduce_division(vexp),
+        symbols::EQUAL => reduce_equality(vexp),
+        _ => match eval.builtins.get(&name) {
+            Some(Builtin::Math) => reduce_math(vexp, eval),
+            Some(Builtin::String) => reduce_string(vexp, eval),
+            Some(Builtin::Collection) => reduce_collection(vexp, eval),
+            Some(Builtin::Predicate) => reduce_predicate(vexp, eval),
+            Some(Builtin::Memory) => reduce_memory(vexp, eval),
+            Some(Builtin::Condition) => reduce_condition(vexp, eval),
+            None => Ok(Expression::list(vexp)),
+        },
+    }
+}
+
+fn evaluate_list(vexp: &[Expression], eval: &mut Evaluator) -> Result<Expression, LispError> {
+    if let Some(Expression::Symbol(s)) = vexp.first() {
+        match *s {
+            symbols::DEFINE_RECORD_TYPE => evaluate_define_record(vexp, eval),
+            symbols::DEFINE => evaluate_define(vexp, eval),
+            symbols::GUARD => evaluate_guard(vexp, eval),
+            symbols::COND => evaluate_cond(vexp, eval),
+            symbols::LAMBDA => evaluate_lambda(vexp, eval),
+            symbols::SPECIALIZE => evaluate_specialize(vexp, eval),
+            symbols::DECLARE_SYMBOLIC => evaluate_declare(vexp, eval),
+            // A name the program binds itself shadows the builtin, parameters are resolved so
+            // this only finds a global or the variable of a handler
+            s if is_builtin(s, eval) && !eval.env.is_defined(s) => {
+                let vexp = evaluate_all(vexp, eval)?;
+                apply_builtin(s, vexp, eval)
+            }
             _ => apply(vexp, eval),
         }
     } else {
-        let new_vexp: Vec<Expression> = vexp.iter().map(|e| evaluate_expression(e, eval)).collect();
-        Expression::List(new_vexp)
+        apply(vexp, eval)
     }
 }
 
-fn evaluate_expression(expression: &Expression, eval: &mut Evaluator) -> Expression {
-    let ret: Expression;
+fn evaluate_expression(
+    expression: &Expression,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    step(eval, || expression.clone())?;
     match expression {
-        Expression::Symbol(s) => ret = eval.env.get(&s).1,
-        Expression::Number(n) => ret = Expression::Number(*n),
-        Expression::Boolean(b) => ret = Expression::Boolean(*b),
-        Expression::List(v) => ret = evaluate_list(v.clone(), eval),
-        Expression::Nil => ret = Expression::Nil,
-        _ => ret = expression.clone(),
+        Expression::Symbol(s) => match eval.env.find(*s) {
+            Some(value) => Ok(value),
+            None => {
+                check_symbolic(*s, eval)?;
+                Ok(expression.clone())
+            }
+        },
+        Expression::Local(s, depth, index) => Ok(eval.env.lookup(*s, *depth, *index)),
+        Expression::List(v) => evaluate_list(v, eval).map_err(|err| locate(err, v)),
+        // Closing over the current bindings specialises the body on them
+        Expression::Lambda(params, body, _) => make_lambda((**params).clone(), body, eval),
+        _ => Ok(expression.clone()),
     }
-    ret
 }
 
-pub fn evaluate(expressions: Vec<Expression>) -> Expression {
-    let mut eval = Evaluator::new();
+pub fn evaluate(
+    expressions: Vec<Expression>,
+    eval: &mut Evaluator,
+) -> Result<Expression, LispError> {
+    eval.index = 0;
+    begin(eval);
 
     let mut e = Expression::Nil;
     while eval.index < expressions.len() {
         if let Some(expression) = expressions.get(eval.index) {
-            e = evaluate_expression(&expression, &mut eval);
+            e = evaluate_expression(expression, eval)?;
             eval.index += 1;
         }
     }
-    e
+    Ok(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Evaluator, UNFOLD_BUDGET, evaluate, reduce_dynamic};
+    use crate::symbols::Symbol;
+    use crate::types::{Expression, LispError};
+    use crate::{interrupt, parser, scanner, stack};
+
+    // Recursion on a symbolic argument that branches, every call unfolds two more
+    const BRANCHING: &str =
+        "(define (h x n) (cond ((= x 0) n) (t (+ (h (- x 1) (+ n 1)) (h (- x 2) (+ n 1))))))";
+
+    /* Evaluates a program, giving what the REPL prints for it. */
+    fn run(program: &str, eval: &mut Evaluator) -> String {
+        match evaluate(parser::parse(scanner::scan(program)), eval) {
+            Ok(e) => e.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn unfolding_branching_recursion_terminates() {
+        let mut eval = Evaluator::new();
+        // Enough for the unfolding budget, far from enough for the exponential unfolding
+        eval.fuel = Some(100_000);
+        run(BRANCHING, &mut eval);
+        let residual = run("(h y 0)", &mut eval);
+        assert!(residual.starts_with("(cond ((= 0 y) 0)"), "{}", residual);
+        assert_eq!(residual.matches("(cond").count(), UNFOLD_BUDGET);
+    }
+
+    #[test]
+    fn limits_hold_in_symbolic_branches() {
+        let mut eval = Evaluator::new();
+        eval.fuel = Some(500);
+        run(BRANCHING, &mut eval);
+        let result = run("(h y 0)", &mut eval);
+        assert!(result.starts_with("fuel limit reached"), "{}", result);
+    }
+
+    #[test]
+    fn deep_recursion_is_a_limit_and_not_an_overflow() {
+        let result = stack::run(|| {
+            let mut eval = Evaluator::new();
+            run("(define (down n) (+ 1 (down (- n 1))))", &mut eval);
+            [run("(down 0)", &mut eval), run("(down 1)", &mut eval)]
+        });
+        for result in result {
+            assert!(result.starts_with("stack limit reached"), "{}", result);
+        }
+    }
+
+    #[test]
+    fn interrupts_stop_symbolic_reduction() {
+        let mut eval = Evaluator::new();
+        eval.interruptible = true;
+        let branch = parser::parse(scanner::scan("(+ y 1)")).remove(0);
+        interrupt::press();
+        assert_eq!(
+            reduce_dynamic(&branch, &mut eval),
+            Err(LispError::Interrupted)
+        );
+        assert_eq!(
+            reduce_dynamic(&branch, &mut eval).unwrap().to_string(),
+            "(+ 1 y)"
+        );
+    }
+
+    /* Whether `s` is still looked up by name somewhere in `e`. */
+    fn by_name(e: &Expression, s: Symbol) -> bool {
+        match e {
+            Expression::Symbol(name) => *name == s,
+            Expression::List(v) => v.iter().any(|e| by_name(e, s)),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn variables_resolve_to_addresses() {
+        let mut eval = Evaluator::new();
+        run(
+            "(define (inv x) (guard (e ((= e x) x) (t (+ x 1))) (cond ((= x 0) (raise x)) (t (/ 1 x)))))",
+            &mut eval,
+        );
+        let Expression::Lambda(_, body, _) = eval.env.get(Symbol::new("inv")) else {
+            panic!("inv isn't a function");
+        };
+        assert!(!by_name(&body, Symbol::new("x")));
+        // The variable of a handler is bound when it runs, by name
+        assert!(by_name(&body, Symbol::new("e")));
+        assert_eq!(run("(inv 0)", &mut eval), "0");
+        assert_eq!(run("(inv 2)", &mut eval), "1/2");
+        // The handler's variable shadows the parameter
+        run(
+            "(define (shadow e) (guard (e (t e)) (raise (+ e 1))))",
+            &mut eval,
+        );
+        assert_eq!(run("(shadow 1)", &mut eval), "2");
+    }
+
+    #[test]
+    fn arithmetic_stays_exact() {
+        let mut eval = Evaluator::new();
+        assert_eq!(run("(/ 1 3)", &mut eval), "1/3");
+        assert_eq!(run("(+ 1/3 2/3)", &mut eval), "1");
+        assert_eq!(run("(/ 6 3)", &mut eval), "2");
+        assert_eq!(
+            run("(* 99999999999999999999 99999999999999999999)", &mut eval),
+            "9999999999999999999800000000000000000001"
+        );
+        // Mixing in an inexact number makes the result inexact
+        assert_eq!(run("(+ 0.5 1/2)", &mut eval), "1.0");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut eval = Evaluator::new();
+        assert_eq!(run("(/ 1 0)", &mut eval), "division by zero in (/ 1 0)");
+        // Inexact division follows IEEE, and its results read back
+        assert_eq!(run("(/ 1.0 0)", &mut eval), "+inf.0");
+        assert_eq!(run("(/ 0.0 0.0)", &mut eval), "+nan.0");
+        assert_eq!(run("(- +inf.0)", &mut eval), "-inf.0");
+    }
+
+    #[test]
+    fn arity_and_optional_and_rest_parameters() {
+        let mut eval = Evaluator::new();
+        run("(define (two a b) a)", &mut eval);
+        assert_eq!(
+            run("(two 1)", &mut eval),
+            "wrong number of arguments in (two 1), expected 2"
+        );
+        run("(define (opt a #:optional (b 10)) (+ a b))", &mut eval);
+        assert_eq!(
+            run("(opt)", &mut eval),
+            "wrong number of arguments in (opt), expected between 1 and 2"
+        );
+        assert_eq!(run("(list (opt 1) (opt 1 2))", &mut eval), "(list 11 3)");
+        run("(define (rst a . r) r)", &mut eval);
+        assert_eq!(
+            run("(list (rst 1) (rst 1 2 3))", &mut eval),
+            "(list nil (2 3))"
+        );
+    }
+
+    #[test]
+    fn curry_mode() {
+        let mut eval = Evaluator::new();
+        run("(define (add a b) (+ a b))", &mut eval);
+        assert_eq!(
+            run("(add 1)", &mut eval),
+            "wrong number of arguments in (add 1), expected 2"
+        );
+        eval.curry = true;
+        assert_eq!(run("(add 1)", &mut eval), "(lambda (b) (+ 1 b))");
+        run("(define inc (add 1))", &mut eval);
+        assert_eq!(run("(inc 2)", &mut eval), "3");
+    }
+
+    #[test]
+    fn specialize_writes_residual_definitions() {
+        let mut eval = Evaluator::new();
+        run(
+            "(define (pw x n) (cond ((= n 0) 1) (t (* x (pw x (- n 1))))))",
+            &mut eval,
+        );
+        assert_eq!(
+            run("(specialize pw x 3)", &mut eval),
+            "((define (pw1 x) (* x x x)))"
+        );
+        // A dynamic recursion stays a call to the specialised function
+        assert_eq!(
+            run("(specialize pw 2 n)", &mut eval),
+            "((define (pw1 n) (cond ((= 0 n) 1) (true (* 2 (pw1 (- n 1)))))))"
+        );
+    }
+
+    #[test]
+    fn unfold_budget_bounds_symbolic_calls() {
+        let mut eval = Evaluator::new();
+        run(
+            "(define (pw x n) (cond ((= n 0) 1) (t (* x (pw x (- n 1))))))",
+            &mut eval,
+        );
+        assert_eq!(run("(pw x 2)", &mut eval), "(* x x)");
+        assert_eq!(
+            run("(pw 2 n)", &mut eval),
+            "(cond ((= 0 n) 1) (true (* 2 (pw 2 (- n 1)))))"
+        );
+        // Without a budget, calls with symbolic arguments stay residual
+        eval.unfold_budget = 0;
+        assert_eq!(run("(pw x 2)", &mut eval), "(pw x 2)");
+    }
+
+    #[test]
+    fn declared_symbolic_variables() {
+        let mut eval = Evaluator::new();
+        assert_eq!(run("(+ lenght 1)", &mut eval), "(+ 1 lenght)");
+        eval.strict = true;
+        assert_eq!(
+            run("(+ lenght 1)", &mut eval),
+            "unbound variable lenght in (+ lenght 1)"
+        );
+        run("(declare-symbolic y)", &mut eval);
+        assert_eq!(run("(+ y 1)", &mut eval), "(+ 1 y)");
+
+        // Declaring a variable turns strict mode on
+        let mut eval = Evaluator::new();
+        run("(declare-symbolic y)", &mut eval);
+        assert!(eval.strict);
+        assert_eq!(
+            run("(+ lenght 1)", &mut eval),
+            "unbound variable lenght in (+ lenght 1)"
+        );
+        assert_eq!(
+            run("(declare-symbolic 1)", &mut eval),
+            "wrong type in (declare-symbolic 1), expected a symbol"
+        );
+    }
+
+    #[test]
+    fn keywords_and_key_parameters() {
+        let mut eval = Evaluator::new();
+        assert_eq!(run(":a", &mut eval), ":a");
+        assert_eq!(run("(type-of :foo)", &mut eval), "'keyword");
+        run("(define (area w #:key (h 2)) (* w h))", &mut eval);
+        assert_eq!(
+            run("(list (area 3) (area 3 :h 4))", &mut eval),
+            "(list 6 12)"
+        );
+        assert_eq!(run("(area 3 :h x)", &mut eval), "(* 3 x)");
+        assert_eq!(
+            run("(area 3 :d 4)", &mut eval),
+            "wrong type in (area 3 :d 4), expected one of the keywords :h"
+        );
+        assert_eq!(
+            run("(area 3 :h)", &mut eval),
+            "wrong number of arguments in (area 3 :h), expected a value after :h"
+        );
+    }
 }
@@ -0,0 +1,367 @@
+use crate::maps::{self, Map};
+use crate::records::Record;
+use crate::types::{Expression, Frame};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+/*
+ * Cycle collector for the values shared behind Rc.
+ * Reference counting frees most values, but not a closure kept in the frame it captures or a vector
+ * holding itself. The frames, vectors, maps and records such cycles go through are tracked weakly,
+ * and a collection finds the ones only referenced by each other: it subtracts the references the
+ * tracked objects hold to each other from their counts, whatever is left over is referenced from
+ * outside, and anything not reachable from those is garbage. Emptying the garbage breaks its cycles.
+ * Other shared values, like lists, are only looked into where nothing else holds them, so a cycle
+ * through a shared list is kept, but nothing reachable is ever freed.
+ */
+
+// Names of the builtins of the collector
+pub const FUNCTIONS: &[&str] = &["gc", "gc-stats"];
+
+// Collections run after this many objects are tracked, at the least
+const THRESHOLD: usize = 4096;
+
+// An object that can be part of a cycle, tracking it doesn't keep it alive
+enum Object {
+    Frame(Weak<Frame>),
+    Vector(Weak<RefCell<Vec<Expression>>>),
+    Map(Weak<RefCell<Map>>),
+    Record(Weak<Record>),
+}
+
+// A tracked object still alive while it is being collected
+enum Node {
+    Frame(Rc<Frame>),
+    Vector(Rc<RefCell<Vec<Expression>>>),
+    Map(Rc<RefCell<Map>>),
+    Record(Rc<Record>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub collections: usize,
+    pub freed: usize,   // Objects freed by all collections
+    pub tracked: usize, // Objects tracked since the start
+    pub live: usize,    // Objects still alive after the last collection
+}
+
+struct Heap {
+    objects: Vec<Object>,
+    threshold: usize, // How many objects there may be until the next collection
+    stats: Stats,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: Vec::new(),
+        threshold: THRESHOLD,
+        stats: Stats::default(),
+    });
+}
+
+impl Object {
+    fn alive(&self) -> bool {
+        match self {
+            Object::Frame(w) => w.strong_count() > 0,
+            Object::Vector(w) => w.strong_count() > 0,
+            Object::Map(w) => w.strong_count() > 0,
+            Object::Record(w) => w.strong_count() > 0,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Object::Frame(w) => w.upgrade().map(Node::Frame),
+            Object::Vector(w) => w.upgrade().map(Node::Vector),
+            Object::Map(w) => w.upgrade().map(Node::Map),
+            Object::Record(w) => w.upgrade().map(Node::Record),
+        }
+    }
+}
+
+/* Tracks a new frame. */
+pub fn track_frame(frame: &Rc<Frame>) {
+    track(Object::Frame(Rc::downgrade(frame)));
+}
+
+/* Tracks a new vector, map or record, other values can't be part of a cycle by themselves. */
+pub fn track_value(e: &Expression) {
+    match e {
+        Expression::Vector(v) => track(Object::Vector(Rc::downgrade(v))),
+        Expression::Map(m) => track(Object::Map(Rc::downgrade(m))),
+        Expression::Record(r) => track(Object::Record(Rc::downgrade(r))),
+        _ => (),
+    }
+}
+
+/* Collects once the objects tracked outgrow the threshold, which then becomes twice the objects left. */
+fn track(object: Object) {
+    let full = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(object);
+        heap.stats.tracked += 1;
+        heap.objects.len() >= heap.threshold
+    });
+    if full {
+        collect();
+        HEAP.with(|heap| {
+            let mut heap = heap.borrow_mut();
+            heap.threshold = THRESHOLD.max(2 * heap.objects.len());
+        });
+    }
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| heap.borrow().stats)
+}
+
+// How far a walk looks into values behind an Rc that aren't tracked
+enum Walk {
+    Owned,                   // Only into the values nothing else holds
+    All(HashSet<*const ()>), // Into all of them, once each
+}
+
+impl Walk {
+    fn enter<T>(&mut self, rc: &Rc<T>) -> bool {
+        match self {
+            Walk::Owned => Rc::strong_count(rc) == 1,
+            Walk::All(seen) => seen.insert(Rc::as_ptr(rc) as *const ()),
+        }
+    }
+}
+
+/* Calls `f` with the address of every tracked object an expression refers to. */
+fn walk(e: &Expression, how: &mut Walk, f: &mut impl FnMut(*const ())) {
+    match e {
+        Expression::List(v) if how.enter(v) => {
+            for e in v.iter() {
+                walk(e, how, f);
+            }
+        }
+        Expression::Quote(e) | Expression::Unquote(e) if how.enter(e) => walk(e, how, f),
+        Expression::Lambda(params, body, scope) => {
+            if how.enter(params) {
+                for (_, default) in params.optional.iter().chain(params.key.iter()) {
+                    walk(default, how, f);
+                }
+            }
+            if how.enter(body) {
+                walk(body, how, f);
+            }
+            if let Some(frame) = scope {
+                f(Rc::as_ptr(frame) as *const ());
+            }
+        }
+        Expression::Vector(v) => f(Rc::as_ptr(v) as *const ()),
+        Expression::Map(m) => f(Rc::as_ptr(m) as *const ()),
+        Expression::Record(r) => f(Rc::as_ptr(r) as *const ()),
+        _ => (),
+    }
+}
+
+impl Node {
+    fn address(&self) -> *const () {
+        match self {
+            Node::Frame(frame) => Rc::as_ptr(frame) as *const (),
+            Node::Vector(v) => Rc::as_ptr(v) as *const (),
+            Node::Map(m) => Rc::as_ptr(m) as *const (),
+            Node::Record(r) => Rc::as_ptr(r) as *const (),
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            Node::Frame(frame) => Rc::strong_count(frame),
+            Node::Vector(v) => Rc::strong_count(v),
+            Node::Map(m) => Rc::strong_count(m),
+            Node::Record(r) => Rc::strong_count(r),
+        }
+    }
+
+    /* Walks the contents, unless they are being changed and can't be looked at. */
+    fn children(&self, how: &mut Walk, f: &mut impl FnMut(*const ())) {
+        let values = match self {
+            Node::Frame(frame) => {
+                if let Some(parent) = frame.parent() {
+                    f(Rc::as_ptr(parent) as *const ());
+                }
+                let Ok(vars) = frame.variables().try_borrow() else {
+                    return;
+                };
+                for (_, e) in vars.iter() {
+                    walk(e, how, f);
+                }
+                return;
+            }
+            Node::Vector(v) => v.try_borrow(),
+            Node::Record(r) => r.values.try_borrow(),
+            Node::Map(m) => {
+                let Ok(m) = m.try_borrow() else {
+                    return;
+                };
+                for (key, value) in m.entries() {
+                    walk(key, how, f);
+                    walk(value, how, f);
+                }
+                return;
+            }
+        };
+        if let Ok(values) = values {
+            for e in values.iter() {
+                walk(e, how, f);
+            }
+        }
+    }
+
+    /* Empties the object, dropping what it refers to. */
+    fn clear(&self) {
+        match self {
+            Node::Frame(frame) => drop(frame.variables().take()),
+            Node::Vector(v) => drop(v.take()),
+            Node::Map(m) => drop(m.take()),
+            Node::Record(r) => drop(r.values.take()),
+        }
+    }
+}
+
+/* Frees the tracked objects only reachable from each other, returns how many there were. */
+pub fn collect() -> usize {
+    let nodes: Vec<Node> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(Object::alive);
+        heap.objects.iter().filter_map(Object::upgrade).collect()
+    });
+    let index: HashMap<*const (), usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.address(), i))
+        .collect();
+
+    // The references from outside, not counting the one held here
+    let mut outside: Vec<usize> = nodes.iter().map(|node| node.count() - 1).collect();
+    for node in nodes.iter() {
+        // If the contents can't be looked at, what they refer to stays counted as referenced from outside
+        node.children(&mut Walk::Owned, &mut |address| {
+            if let Some(i) = index.get(&address) {
+                outside[*i] -= 1;
+            }
+        });
+    }
+
+    // Everything reachable from the objects referenced from outside is alive
+    let mut alive = vec![false; nodes.len()];
+    let mut pending: Vec<usize> = (0..nodes.len()).filter(|i| outside[*i] > 0).collect();
+    let mut how = Walk::All(HashSet::new());
+    while let Some(i) = pending.pop() {
+        if alive[i] {
+            continue;
+        }
+        alive[i] = true;
+        nodes[i].children(&mut how, &mut |address| {
+            if let Some(j) = index.get(&address) {
+                pending.push(*j);
+            }
+        });
+    }
+
+    let mut freed = 0;
+    for (node, alive) in nodes.iter().zip(alive) {
+        if !alive {
+            node.clear();
+            freed += 1;
+        }
+    }
+    // The garbage goes once nothing holds it here either
+    drop(nodes);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(Object::alive);
+        heap.stats.collections += 1;
+        heap.stats.freed += freed;
+        heap.stats.live = heap.objects.len();
+    });
+    freed
+}
+
+/* Calls `(gc)`, which collects and returns how many objects it freed, or `(gc-stats)`. */
+pub fn call(name: &str) -> Expression {
+    match name {
+        "gc" => Expression::Number((collect() as i64).into()),
+        _ => {
+            let stats = stats();
+            let mut map = Map::default();
+            for (key, n) in [
+                ("collections", stats.collections),
+                ("freed", stats.freed),
+                ("tracked", stats.tracked),
+                ("live", stats.live),
+            ] {
+                map.insert(
+                    Expression::Keyword(key.to_string()),
+                    Expression::Number((n as i64).into()),
+                );
+            }
+            maps::from(map)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::collect;
+    use crate::evaluator::{self, Evaluator};
+    use crate::{parser, scanner};
+
+    fn run(program: &str, eval: &mut Evaluator) -> String {
+        let result = evaluator::evaluate(parser::parse(scanner::scan(program)), eval);
+        result.unwrap().to_string()
+    }
+
+    #[test]
+    fn frees_cycles() {
+        let mut eval = Evaluator::new();
+        collect();
+        run("(define v (vector 1 2)) (vector-set! v 0 v)", &mut eval);
+        run(
+            "(define h (hash)) (define w (vector h)) (hash-set! h :w w)",
+            &mut eval,
+        );
+        assert_eq!(collect(), 0);
+        run("(define v 0) (define h 0) (define w 0)", &mut eval);
+        assert_eq!(collect(), 3);
+    }
+
+    #[test]
+    fn keeps_what_is_reachable() {
+        let mut eval = Evaluator::new();
+        collect();
+        run(
+            "(define-record-type node (make-node next) node? (next node-next set-node-next!))
+             (define a (make-node 0)) (define b (make-node a)) (set-node-next! a b)
+             (define box (vector (lambda (x) (+ x 1))))",
+            &mut eval,
+        );
+        assert_eq!(collect(), 0);
+        assert_eq!(run("((vector-ref box 0) 2)", &mut eval), "3");
+        // Still reachable through the other node
+        run("(define b 0)", &mut eval);
+        assert_eq!(collect(), 0);
+        run("(define a 0)", &mut eval);
+        assert_eq!(collect(), 2);
+    }
+
+    #[test]
+    fn builtins() {
+        let mut eval = Evaluator::new();
+        run(
+            "(define v (vector 0)) (vector-set! v 0 v) (define v 0)",
+            &mut eval,
+        );
+        assert_eq!(run("(gc)", &mut eval), "1");
+        assert_eq!(run("(hash-ref (gc-stats) :freed)", &mut eval), "1");
+        // Nothing is collected while a body is reduced ahead of time
+        assert_eq!(run("(define (later) (gc))", &mut eval), "(lambda () (gc))");
+    }
+}
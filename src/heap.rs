@@ -0,0 +1,59 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/*
+ * Global allocator counting the memory each thread allocates, for the heap limit of the evaluator
+ * and the allocation benchmark. Counts are per thread, so tests running alongside don't add to them.
+ */
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) }; // Bytes allocated in all
+    static LIVE: Cell<usize> = const { Cell::new(0) };      // Bytes allocated and not freed yet
+}
+
+// The counts are unavailable while a thread is being torn down, those allocations aren't counted
+fn count(allocated: usize, freed: usize) {
+    let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+    let _ = ALLOCATED.try_with(|n| n.set(n.get() + allocated));
+    // Memory may be freed by another thread than the one that allocated it
+    let _ = LIVE.try_with(|n| n.set((n.get() + allocated).saturating_sub(freed)));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size(), 0);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = LIVE.try_with(|n| n.set(n.get().saturating_sub(layout.size())));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size, layout.size());
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/* The number of allocations the current thread made. */
+#[cfg(test)]
+pub fn allocations() -> usize {
+    ALLOCATIONS.get()
+}
+
+/* The bytes the current thread allocated, freed or not. */
+#[cfg(test)]
+pub fn allocated() -> usize {
+    ALLOCATED.get()
+}
+
+/* The bytes the current thread has in use. */
+pub fn live() -> usize {
+    LIVE.get()
+}
@@ -0,0 +1,998 @@
+use crate::compiler::{Chunk, Function, Op};
+use crate::conditions;
+use crate::evaluator::Evaluator;
+use crate::gc;
+use crate::maps::Map;
+use crate::number::{BigInt, Number};
+use crate::records::{Access, Procedure, Record, RecordType};
+use crate::symbols::Symbol;
+use crate::types::{Expression, Frame, Params, Scope};
+use crate::vm::Machine;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+
+/*
+ * Images of the global environment of a module and its compiled functions, loaded without parsing anything.
+ *
+ * An image is a header followed by a payload:
+ *   header     "LLIM", the format version (u32), the length of the payload (u64) and its FNV-1a checksum (u64)
+ *   symbols    the names of the symbols in the image, symbols are their index in this table
+ *   cells      the shared values, frames, vectors, maps, records and record types, without their contents
+ *   constants  the constant pool, every expression a function, global or cell refers to
+ *   contents   the contents of the cells, which may refer back to constants and cells
+ *   functions  compiled functions, their body is a constant so the closures sharing it find their code
+ *   globals    the global variables, as a symbol and a constant
+ * Each section starts with its number of entries, numbers are little endian and lengths are u32.
+ * An entry only refers to entries of an earlier section or earlier in its own section, except for
+ * cells, which are made empty first so the values in them can form cycles.
+ */
+
+const MAGIC: &[u8; 4] = b"LLIM";
+pub const VERSION: u32 = 3;
+const HEADER: usize = 24;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Magic,             // Not an image
+    Version(u32),      // An image of another version of the format
+    Checksum,          // The payload doesn't match its checksum
+    Malformed(String), // What couldn't be read
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::Magic => write!(f, "not an image"),
+            ImageError::Version(v) => {
+                write!(f, "image of version {}, expected version {}", v, VERSION)
+            }
+            ImageError::Checksum => write!(f, "the image is corrupted"),
+            ImageError::Malformed(what) => write!(f, "malformed image, bad {}", what),
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+/* Writes the global environment and the functions the machine compiled for it to a file. */
+pub fn save(path: &str, eval: &Evaluator, machine: &Machine) -> Result<(), ImageError> {
+    fs::write(path, encode(eval, machine))?;
+    Ok(())
+}
+
+/* Loads an image into the global environment, the machine gets its compiled functions. */
+pub fn load(
+    path: &str,
+    eval: &mut Evaluator,
+    machine: Option<&mut Machine>,
+) -> Result<(), ImageError> {
+    decode(&fs::read(path)?, eval, machine)
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Tags of the expressions
+const NIL: u8 = 0;
+const SYMBOL: u8 = 1;
+const LOCAL: u8 = 2;
+const INTEGER: u8 = 3;
+const RATIONAL: u8 = 4;
+const REAL: u8 = 5;
+const STRING: u8 = 6;
+const CHAR: u8 = 7;
+const KEYWORD: u8 = 8;
+const FALSE: u8 = 9;
+const TRUE: u8 = 10;
+const QUOTE: u8 = 11;
+const UNQUOTE: u8 = 12;
+const LIST: u8 = 13;
+const LAMBDA: u8 = 14;
+const CELL: u8 = 15; // A vector, map or record
+const PROCEDURE: u8 = 16;
+
+// Tags of the cells
+const FRAME: u8 = 0;
+const VECTOR: u8 = 1;
+const MAP: u8 = 2;
+const RECORD: u8 = 3;
+const TYPE: u8 = 4;
+const CONDITION: u8 = 5; // The type of the conditions, which is builtin
+
+// Tags of the procedures of record types
+const MAKE: u8 = 0;
+const IS: u8 = 1;
+const REF: u8 = 2;
+const SET: u8 = 3;
+
+// A shared value, kept in the image once however many values refer to it
+enum Cell {
+    Frame(Rc<Frame>),
+    Vector(Rc<RefCell<Vec<Expression>>>),
+    Map(Rc<RefCell<Map>>),
+    Record(Rc<Record>),
+    Type(Rc<RecordType>), // Has no contents, its records and procedures only need it to be the same
+}
+
+fn put_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend((n as u32).to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+/* Collects the sections of an image, entries are numbered as they are found. */
+#[derive(Default)]
+struct Writer {
+    symbols: HashMap<Symbol, usize>,
+    names: Vec<u8>,
+    cells: HashMap<*const (), usize>,
+    shells: Vec<u8>,
+    pending: VecDeque<Cell>, // Cells whose contents are still to be written
+    contents: Vec<u8>,
+    constants: Vec<u8>,
+    constant_count: usize,
+    bodies: HashMap<*const Expression, usize>,
+    compiled: Vec<Rc<Function>>, // Functions of bodies found, still to be written
+    functions: HashMap<*const Function, usize>,
+    code: Vec<u8>,
+}
+
+fn encode(eval: &Evaluator, machine: &Machine) -> Vec<u8> {
+    let mut writer = Writer::default();
+    let mut globals = Vec::new();
+    let env = eval.env.globals();
+    put_u32(&mut globals, env.len());
+    for (s, e) in env {
+        writer.symbol(s, &mut globals);
+        let constant = writer.constant(&e, machine);
+        put_u32(&mut globals, constant);
+    }
+    // Contents and functions find more cells and functions until there are none left
+    loop {
+        if let Some(cell) = writer.pending.pop_front() {
+            let mut out = Vec::new();
+            writer.contents(&cell, &mut out, machine);
+            writer.contents.extend(out);
+        } else if let Some(function) = writer.compiled.pop() {
+            writer.function(&function, machine);
+        } else {
+            break;
+        }
+    }
+
+    let mut payload = Vec::new();
+    put_u32(&mut payload, writer.symbols.len());
+    payload.extend(writer.names);
+    put_u32(&mut payload, writer.cells.len());
+    payload.extend(writer.shells);
+    put_u32(&mut payload, writer.constant_count);
+    payload.extend(writer.constants);
+    payload.extend(writer.contents);
+    put_u32(&mut payload, writer.functions.len());
+    payload.extend(writer.code);
+    payload.extend(globals);
+
+    let mut image = MAGIC.to_vec();
+    image.extend(VERSION.to_le_bytes());
+    image.extend((payload.len() as u64).to_le_bytes());
+    image.extend(checksum(&payload).to_le_bytes());
+    image.extend(payload);
+    image
+}
+
+impl Writer {
+    fn symbol(&mut self, s: Symbol, out: &mut Vec<u8>) {
+        let next = self.symbols.len();
+        let index = *self.symbols.entry(s).or_insert_with(|| {
+            put_str(&mut self.names, s.name());
+            next
+        });
+        put_u32(out, index);
+    }
+
+    /* Adds an expression to the constant pool, after the bodies of the functions in it. */
+    fn constant(&mut self, e: &Expression, machine: &Machine) -> usize {
+        let mut out = Vec::new();
+        self.expression(e, &mut out, machine);
+        self.constants.extend(out);
+        self.constant_count += 1;
+        self.constant_count - 1
+    }
+
+    /* Bodies are shared by their closures and compiled function, so they are only added once. */
+    fn body(&mut self, body: &Rc<Expression>, machine: &Machine) -> usize {
+        if let Some(index) = self.bodies.get(&Rc::as_ptr(body)) {
+            return *index;
+        }
+        let index = self.constant(body, machine);
+        self.bodies.insert(Rc::as_ptr(body), index);
+        if let Some(function) = machine.compiled(body) {
+            self.compiled.push(function.clone());
+        }
+        index
+    }
+
+    /* The index of a cell, new cells are added with what they need to be made and their contents wait. */
+    fn cell(&mut self, cell: Cell, machine: &Machine) -> usize {
+        let ptr = match &cell {
+            Cell::Frame(frame) => Rc::as_ptr(frame) as *const (),
+            Cell::Vector(v) => Rc::as_ptr(v) as *const (),
+            Cell::Map(m) => Rc::as_ptr(m) as *const (),
+            Cell::Record(r) => Rc::as_ptr(r) as *const (),
+            Cell::Type(kind) => Rc::as_ptr(kind) as *const (),
+        };
+        if let Some(index) = self.cells.get(&ptr) {
+            return *index;
+        }
+        let mut shell = Vec::new();
+        match &cell {
+            // The parent of a frame can't change, so it is made first
+            Cell::Frame(frame) => {
+                shell.push(FRAME);
+                self.scope(frame.parent(), &mut shell, machine);
+            }
+            Cell::Vector(_) => shell.push(VECTOR),
+            Cell::Map(_) => shell.push(MAP),
+            // Likewise for the type of a record
+            Cell::Record(r) => {
+                shell.push(RECORD);
+                let kind = self.cell(Cell::Type(r.kind.clone()), machine);
+                put_u32(&mut shell, kind);
+            }
+            Cell::Type(kind) if *kind == conditions::condition_type() => shell.push(CONDITION),
+            Cell::Type(kind) => {
+                shell.push(TYPE);
+                put_str(&mut shell, &kind.name);
+                put_u32(&mut shell, kind.fields.len());
+                for field in kind.fields.iter() {
+                    put_str(&mut shell, field);
+                }
+            }
+        }
+        let index = self.cells.len();
+        self.cells.insert(ptr, index);
+        self.shells.extend(shell);
+        self.pending.push_back(cell);
+        index
+    }
+
+    /* A scope is 0 for the global one or one more than the index of its frame. */
+    fn scope(&mut self, scope: &Scope, out: &mut Vec<u8>, machine: &Machine) {
+        match scope {
+            Some(frame) => {
+                let index = self.cell(Cell::Frame(frame.clone()), machine);
+                put_u32(out, index + 1);
+            }
+            None => put_u32(out, 0),
+        }
+    }
+
+    fn contents(&mut self, cell: &Cell, out: &mut Vec<u8>, machine: &Machine) {
+        let pairs = match cell {
+            Cell::Frame(frame) => {
+                let vars = frame.vars();
+                put_u32(out, vars.len());
+                for (s, e) in vars {
+                    self.symbol(s, out);
+                    self.expression(&e, out, machine);
+                }
+                return;
+            }
+            Cell::Vector(v) => {
+                self.expressions(&v.borrow(), out, machine);
+                return;
+            }
+            Cell::Record(r) => {
+                self.expressions(&r.values.borrow(), out, machine);
+                return;
+            }
+            Cell::Type(_) => return,
+            Cell::Map(m) => m.borrow().entries().to_vec(),
+        };
+        put_u32(out, pairs.len());
+        for (key, value) in pairs {
+            self.expression(&key, out, machine);
+            self.expression(&value, out, machine);
+        }
+    }
+
+    fn expressions(&mut self, v: &[Expression], out: &mut Vec<u8>, machine: &Machine) {
+        put_u32(out, v.len());
+        for e in v.iter() {
+            self.expression(e, out, machine);
+        }
+    }
+
+    fn expression(&mut self, e: &Expression, out: &mut Vec<u8>, machine: &Machine) {
+        match e {
+            Expression::Nil => out.push(NIL),
+            Expression::Symbol(s) => {
+                out.push(SYMBOL);
+                self.symbol(*s, out);
+            }
+            Expression::Local(s, depth, index) => {
+                out.push(LOCAL);
+                self.symbol(*s, out);
+                put_u32(out, *depth);
+                put_u32(out, *index);
+            }
+            Expression::Number(Number::Integer(n)) => {
+                out.push(INTEGER);
+                put_str(out, &n.to_string());
+            }
+            Expression::Number(Number::Rational(n, d)) => {
+                out.push(RATIONAL);
+                put_str(out, &n.to_string());
+                put_str(out, &d.to_string());
+            }
+            Expression::Number(Number::Real(r)) => {
+                out.push(REAL);
+                out.extend(r.to_bits().to_le_bytes());
+            }
+            Expression::String(s) => {
+                out.push(STRING);
+                put_str(out, s);
+            }
+            Expression::Char(c) => {
+                out.push(CHAR);
+                put_u32(out, *c as usize);
+            }
+            Expression::Keyword(k) => {
+                out.push(KEYWORD);
+                put_str(out, k);
+            }
+            Expression::Boolean(b) => out.push(if *b { TRUE } else { FALSE }),
+            Expression::Quote(e) => {
+                out.push(QUOTE);
+                self.expression(e, out, machine);
+            }
+            Expression::Unquote(e) => {
+                out.push(UNQUOTE);
+                self.expression(e, out, machine);
+            }
+            Expression::List(v) => {
+                out.push(LIST);
+                self.expressions(v, out, machine);
+            }
+            Expression::Lambda(params, body, scope) => {
+                out.push(LAMBDA);
+                self.params(params, out, machine);
+                let body = self.body(body, machine);
+                put_u32(out, body);
+                self.scope(scope, out, machine);
+            }
+            Expression::Vector(v) => {
+                out.push(CELL);
+                let index = self.cell(Cell::Vector(v.clone()), machine);
+                put_u32(out, index);
+            }
+            Expression::Map(m) => {
+                out.push(CELL);
+                let index = self.cell(Cell::Map(m.clone()), machine);
+                put_u32(out, index);
+            }
+            Expression::Record(r) => {
+                out.push(CELL);
+                let index = self.cell(Cell::Record(r.clone()), machine);
+                put_u32(out, index);
+            }
+            Expression::Procedure(p) => {
+                out.push(PROCEDURE);
+                self.symbol(p.name, out);
+                let kind = self.cell(Cell::Type(p.kind.clone()), machine);
+                put_u32(out, kind);
+                match &p.access {
+                    Access::Make(filled) => {
+                        out.push(MAKE);
+                        put_u32(out, filled.len());
+                        for i in filled.iter() {
+                            put_u32(out, *i);
+                        }
+                    }
+                    Access::Is => out.push(IS),
+                    Access::Ref(i) => {
+                        out.push(REF);
+                        put_u32(out, *i);
+                    }
+                    Access::Set(i) => {
+                        out.push(SET);
+                        put_u32(out, *i);
+                    }
+                }
+            }
+        }
+    }
+
+    fn params(&mut self, params: &Params, out: &mut Vec<u8>, machine: &Machine) {
+        put_u32(out, params.required.len());
+        for s in params.required.iter() {
+            self.symbol(*s, out);
+        }
+        for defaults in [&params.optional, &params.key] {
+            put_u32(out, defaults.len());
+            for (s, default) in defaults.iter() {
+                self.symbol(*s, out);
+                self.expression(default, out, machine);
+            }
+        }
+        match params.rest {
+            Some(s) => {
+                out.push(1);
+                self.symbol(s, out);
+            }
+            None => out.push(0),
+        }
+    }
+
+    /* Adds a compiled function after the functions nested in it. */
+    fn function(&mut self, function: &Rc<Function>, machine: &Machine) -> usize {
+        if let Some(index) = self.functions.get(&Rc::as_ptr(function)) {
+            return *index;
+        }
+        let mut out = Vec::new();
+        self.params(&function.params, &mut out, machine);
+        let body = self.body(&function.body, machine);
+        put_u32(&mut out, body);
+        let chunk = &function.chunk;
+        put_u32(&mut out, chunk.constants.len());
+        for e in chunk.constants.iter() {
+            let constant = self.constant(e, machine);
+            put_u32(&mut out, constant);
+        }
+        put_u32(&mut out, chunk.functions.len());
+        for nested in chunk.functions.iter() {
+            let index = self.function(nested, machine);
+            put_u32(&mut out, index);
+        }
+        put_u32(&mut out, chunk.code.len());
+        for op in chunk.code.iter() {
+            self.op(op, &mut out);
+        }
+        let index = self.functions.len();
+        self.functions.insert(Rc::as_ptr(function), index);
+        self.code.extend(out);
+        index
+    }
+
+    fn op(&mut self, op: &Op, out: &mut Vec<u8>) {
+        match op {
+            Op::Constant(i) => {
+                out.push(0);
+                put_u32(out, *i);
+            }
+            Op::Local(s, depth, index) => {
+                out.push(1);
+                self.symbol(*s, out);
+                put_u32(out, *depth);
+                put_u32(out, *index);
+            }
+            Op::Variable(s) => {
+                out.push(2);
+                self.symbol(*s, out);
+            }
+            Op::Define(s) => {
+                out.push(3);
+                self.symbol(*s, out);
+            }
+            Op::Closure(i) => {
+                out.push(4);
+                put_u32(out, *i);
+            }
+            Op::Builtin(s, count) => {
+                out.push(5);
+                self.symbol(*s, out);
+                put_u32(out, *count);
+            }
+            Op::Call(count) => {
+                out.push(6);
+                put_u32(out, *count);
+            }
+            Op::TailCall(count) => {
+                out.push(7);
+                put_u32(out, *count);
+            }
+            Op::Jump(target) => {
+                out.push(8);
+                put_u32(out, *target);
+            }
+            Op::JumpIfFalse(target) => {
+                out.push(9);
+                put_u32(out, *target);
+            }
+            Op::Pop => out.push(10),
+            Op::Return => out.push(11),
+            Op::Guard(target) => {
+                out.push(12);
+                put_u32(out, *target);
+            }
+            Op::EndGuard => out.push(13),
+        }
+    }
+}
+
+/* Reads the sections of an image back in the order they were written. */
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    symbols: Vec<Symbol>,
+    cells: Vec<Cell>,
+    constants: Vec<Rc<Expression>>,
+    functions: Vec<Rc<Function>>,
+}
+
+fn malformed<T>(what: &str) -> Result<T, ImageError> {
+    Err(ImageError::Malformed(what.to_string()))
+}
+
+fn decode(
+    image: &[u8],
+    eval: &mut Evaluator,
+    machine: Option<&mut Machine>,
+) -> Result<(), ImageError> {
+    if image.len() < HEADER || &image[..4] != MAGIC {
+        return Err(ImageError::Magic);
+    }
+    let version = u32::from_le_bytes(image[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(ImageError::Version(version));
+    }
+    let length = u64::from_le_bytes(image[8..16].try_into().unwrap());
+    let sum = u64::from_le_bytes(image[16..24].try_into().unwrap());
+    let payload = &image[HEADER..];
+    if payload.len() as u64 != length || checksum(payload) != sum {
+        return Err(ImageError::Checksum);
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        at: 0,
+        symbols: Vec::new(),
+        cells: Vec::new(),
+        constants: Vec::new(),
+        functions: Vec::new(),
+    };
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        reader.symbols.push(Symbol::new(&name));
+    }
+    for _ in 0..reader.u32()? {
+        let cell = reader.shell()?;
+        reader.cells.push(cell);
+    }
+    for _ in 0..reader.u32()? {
+        let e = reader.expression()?;
+        reader.constants.push(Rc::new(e));
+    }
+    for i in 0..reader.cells.len() {
+        reader.contents(i)?;
+    }
+    for _ in 0..reader.u32()? {
+        let function = reader.function()?;
+        reader.functions.push(Rc::new(function));
+    }
+    let mut globals = Vec::new();
+    for _ in 0..reader.u32()? {
+        let s = reader.symbol()?;
+        let e = reader.constant()?;
+        globals.push((s, (*e).clone()));
+    }
+    if reader.at != payload.len() {
+        return malformed("length");
+    }
+
+    for (s, e) in globals {
+        eval.env.global_push(s, e);
+    }
+    if let Some(machine) = machine {
+        for function in reader.functions {
+            machine.install(function);
+        }
+    }
+    Ok(())
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], ImageError> {
+        if self.bytes.len() - self.at < n {
+            return malformed("length");
+        }
+        self.at += n;
+        Ok(&self.bytes[self.at - n..self.at])
+    }
+
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, ImageError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, ImageError> {
+        let n = self.u32()?;
+        match std::str::from_utf8(self.take(n)?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => malformed("string"),
+        }
+    }
+
+    fn big(&mut self) -> Result<BigInt, ImageError> {
+        match BigInt::parse(&self.string()?) {
+            Some(n) => Ok(n),
+            None => malformed("number"),
+        }
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, ImageError> {
+        let i = self.u32()?;
+        match self.symbols.get(i) {
+            Some(s) => Ok(*s),
+            None => malformed("symbol"),
+        }
+    }
+
+    fn constant(&mut self) -> Result<Rc<Expression>, ImageError> {
+        let i = self.u32()?;
+        match self.constants.get(i) {
+            Some(e) => Ok(e.clone()),
+            None => malformed("constant"),
+        }
+    }
+
+    fn scope(&mut self) -> Result<Scope, ImageError> {
+        match self.u32()? {
+            0 => Ok(None),
+            i => match self.cells.get(i - 1) {
+                Some(Cell::Frame(frame)) => Ok(Some(frame.clone())),
+                _ => malformed("scope"),
+            },
+        }
+    }
+
+    fn kind(&mut self) -> Result<Rc<RecordType>, ImageError> {
+        match self.u32().map(|i| self.cells.get(i))? {
+            Some(Cell::Type(kind)) => Ok(kind.clone()),
+            _ => malformed("record type"),
+        }
+    }
+
+    fn shell(&mut self) -> Result<Cell, ImageError> {
+        match self.u8()? {
+            FRAME => Ok(Cell::Frame(Frame::new(self.scope()?))),
+            VECTOR => {
+                let v = Rc::new(RefCell::new(Vec::new()));
+                gc::track_value(&Expression::Vector(v.clone()));
+                Ok(Cell::Vector(v))
+            }
+            MAP => {
+                let m = Rc::new(RefCell::new(Map::default()));
+                gc::track_value(&Expression::Map(m.clone()));
+                Ok(Cell::Map(m))
+            }
+            RECORD => {
+                let r = Rc::new(Record {
+                    kind: self.kind()?,
+                    values: RefCell::new(Vec::new()),
+                });
+                gc::track_value(&Expression::Record(r.clone()));
+                Ok(Cell::Record(r))
+            }
+            TYPE => {
+                let name = self.string()?;
+                let fields = (0..self.u32()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?;
+                Ok(Cell::Type(Rc::new(RecordType { name, fields })))
+            }
+            CONDITION => Ok(Cell::Type(conditions::condition_type())),
+            _ => malformed("cell"),
+        }
+    }
+
+    fn contents(&mut self, i: usize) -> Result<(), ImageError> {
+        match &self.cells[i] {
+            Cell::Frame(frame) => {
+                let frame = frame.clone();
+                for _ in 0..self.u32()? {
+                    let s = self.symbol()?;
+                    frame.bind(s, self.expression()?);
+                }
+            }
+            Cell::Vector(v) => {
+                let v = v.clone();
+                *v.borrow_mut() = self.expressions()?;
+            }
+            Cell::Record(r) => {
+                let r = r.clone();
+                let values = self.expressions()?;
+                if values.len() != r.kind.fields.len() {
+                    return malformed("record");
+                }
+                *r.values.borrow_mut() = values;
+            }
+            Cell::Type(_) => (),
+            Cell::Map(m) => {
+                let m = m.clone();
+                for _ in 0..self.u32()? {
+                    let key = self.expression()?;
+                    let value = self.expression()?;
+                    m.borrow_mut().insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expressions(&mut self) -> Result<Vec<Expression>, ImageError> {
+        (0..self.u32()?).map(|_| self.expression()).collect()
+    }
+
+    fn expression(&mut self) -> Result<Expression, ImageError> {
+        Ok(match self.u8()? {
+            NIL => Expression::Nil,
+            SYMBOL => Expression::Symbol(self.symbol()?),
+            LOCAL => Expression::Local(self.symbol()?, self.u32()?, self.u32()?),
+            INTEGER => Expression::Number(Number::Integer(self.big()?)),
+            RATIONAL => match Number::rational(self.big()?, self.big()?) {
+                Some(n) => Expression::Number(n),
+                None => return malformed("number"),
+            },
+            REAL => {
+                let bits = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                Expression::Number(Number::Real(f64::from_bits(bits)))
+            }
+            STRING => Expression::String(self.string()?),
+            CHAR => match char::from_u32(self.u32()? as u32) {
+                Some(c) => Expression::Char(c),
+                None => return malformed("character"),
+            },
+            KEYWORD => Expression::Keyword(self.string()?),
+            FALSE => Expression::Boolean(false),
+            TRUE => Expression::Boolean(true),
+            QUOTE => Expression::quote(self.expression()?),
+            UNQUOTE => Expression::Unquote(Rc::new(self.expression()?)),
+            LIST => Expression::list(self.expressions()?),
+            LAMBDA => {
+                let params = self.params()?;
+                let body = self.constant()?;
+                Expression::Lambda(Rc::new(params), body, self.scope()?)
+            }
+            CELL => match self.u32().map(|i| self.cells.get(i))? {
+                Some(Cell::Vector(v)) => Expression::Vector(v.clone()),
+                Some(Cell::Map(m)) => Expression::Map(m.clone()),
+                Some(Cell::Record(r)) => Expression::Record(r.clone()),
+                _ => return malformed("cell"),
+            },
+            PROCEDURE => {
+                let name = self.symbol()?;
+                let kind = self.kind()?;
+                let access = match self.u8()? {
+                    MAKE => Access::Make(
+                        (0..self.u32()?)
+                            .map(|_| self.u32())
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    IS => Access::Is,
+                    REF => Access::Ref(self.u32()?),
+                    SET => Access::Set(self.u32()?),
+                    _ => return malformed("procedure"),
+                };
+                let fields = match &access {
+                    Access::Make(filled) => filled.clone(),
+                    Access::Is => Vec::new(),
+                    Access::Ref(i) | Access::Set(i) => vec![*i],
+                };
+                if fields.iter().any(|i| *i >= kind.fields.len()) {
+                    return malformed("procedure");
+                }
+                Expression::Procedure(Rc::new(Procedure { name, kind, access }))
+            }
+            _ => return malformed("expression"),
+        })
+    }
+
+    fn params(&mut self) -> Result<Params, ImageError> {
+        let required = (0..self.u32()?)
+            .map(|_| self.symbol())
+            .collect::<Result<_, _>>()?;
+        let mut defaults = Vec::new();
+        for _ in 0..2 {
+            let mut names = Vec::new();
+            for _ in 0..self.u32()? {
+                names.push((self.symbol()?, self.expression()?));
+            }
+            defaults.push(names);
+        }
+        let rest = match self.u8()? {
+            0 => None,
+            _ => Some(self.symbol()?),
+        };
+        let key = defaults.pop().unwrap();
+        let optional = defaults.pop().unwrap();
+        Ok(Params {
+            required,
+            optional,
+            key,
+            rest,
+        })
+    }
+
+    fn function(&mut self) -> Result<Function, ImageError> {
+        let params = self.params()?;
+        let body = self.constant()?;
+        let mut chunk = Chunk::default();
+        for _ in 0..self.u32()? {
+            chunk.constants.push((*self.constant()?).clone());
+        }
+        for _ in 0..self.u32()? {
+            match self.u32().map(|i| self.functions.get(i))? {
+                Some(nested) => chunk.functions.push(nested.clone()),
+                None => return malformed("function"),
+            }
+        }
+        for _ in 0..self.u32()? {
+            let op = self.op()?;
+            chunk.code.push(op);
+        }
+        Ok(Function {
+            params,
+            body,
+            chunk,
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, ImageError> {
+        Ok(match self.u8()? {
+            0 => Op::Constant(self.u32()?),
+            1 => Op::Local(self.symbol()?, self.u32()?, self.u32()?),
+            2 => Op::Variable(self.symbol()?),
+            3 => Op::Define(self.symbol()?),
+            4 => Op::Closure(self.u32()?),
+            5 => Op::Builtin(self.symbol()?, self.u32()?),
+            6 => Op::Call(self.u32()?),
+            7 => Op::TailCall(self.u32()?),
+            8 => Op::Jump(self.u32()?),
+            9 => Op::JumpIfFalse(self.u32()?),
+            10 => Op::Pop,
+            11 => Op::Return,
+            12 => Op::Guard(self.u32()?),
+            13 => Op::EndGuard,
+            _ => return malformed("instruction"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HEADER, ImageError, checksum, decode, encode};
+    use crate::evaluator::Evaluator;
+    use crate::symbols::Symbol;
+    use crate::types::Expression;
+    use crate::vm::Machine;
+    use crate::{parser, scanner};
+
+    const MODULE: &str = "(define (fact n) (cond ((= n 0) 1) (t (* n (fact (- n 1))))))
+                          (define (adder k) (lambda (x) (+ x k)))
+                          (define add3 (adder 3))
+                          (define v (vector 1 (/ 1 3) 2.5 #\\a \"s\" :k))
+                          (define m (hash :a 1 :b v))
+                          (define-record-type point (make-point x y) point? (x point-x) (y point-y))
+                          (define p (make-point 1 '(a b)))
+                          (define c (guard (e (t e)) (error \"bad\" 1)))";
+
+    /* Runs a module on a machine and returns its image. */
+    fn image(module: &str) -> Vec<u8> {
+        let mut eval = Evaluator::new();
+        let mut machine = Machine::new();
+        machine
+            .run(parser::parse(scanner::scan(module)), &mut eval)
+            .unwrap();
+        encode(&eval, &machine)
+    }
+
+    fn run(program: &str, eval: &mut Evaluator, machine: &mut Machine) -> String {
+        let result = machine.run(parser::parse(scanner::scan(program)), eval);
+        result.unwrap().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = image(MODULE);
+        let mut eval = Evaluator::new();
+        let mut machine = Machine::new();
+        decode(&bytes, &mut eval, Some(&mut machine)).unwrap();
+        assert_eq!(
+            run("(fact 20)", &mut eval, &mut machine),
+            "2432902008176640000"
+        );
+        assert_eq!(run("(add3 4)", &mut eval, &mut machine), "7");
+        assert_eq!(
+            run("v", &mut eval, &mut machine),
+            "#(1 1/3 2.5 #\\a \"s\" :k)"
+        );
+        assert_eq!(run("(point-y p)", &mut eval, &mut machine), "'(a b)");
+        assert_eq!(
+            run("(condition-irritants c)", &mut eval, &mut machine),
+            "(1)"
+        );
+        // The record and the procedures still share their type
+        assert_eq!(run("(point? p)", &mut eval, &mut machine), "true");
+        // The map still holds the same vector as the global
+        run("(vector-set! v 0 9)", &mut eval, &mut machine);
+        assert_eq!(
+            run("(vector-ref (hash-ref m :b) 0)", &mut eval, &mut machine),
+            "9"
+        );
+        // The functions come compiled
+        let Expression::Lambda(_, body, _) = eval.env.get(Symbol::new("fact")) else {
+            panic!("fact isn't a function");
+        };
+        assert!(machine.compiled(&body).is_some());
+        // And images are the same every time
+        assert_eq!(bytes, image(MODULE));
+    }
+
+    #[test]
+    fn tree_walker_loads_images() {
+        let mut eval = Evaluator::new();
+        decode(&image(MODULE), &mut eval, None).unwrap();
+        let result = crate::evaluator::evaluate(
+            parser::parse(scanner::scan("(+ (add3 1) (fact 3))")),
+            &mut eval,
+        );
+        assert_eq!(result.unwrap().to_string(), "10");
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let mut image = image("(define x 1)");
+        let mut eval = Evaluator::new();
+        assert!(matches!(
+            decode(b"not an image at all, really", &mut eval, None),
+            Err(ImageError::Magic)
+        ));
+        let last = image.len() - 1;
+        image[last] ^= 1;
+        assert!(matches!(
+            decode(&image, &mut eval, None),
+            Err(ImageError::Checksum)
+        ));
+        image[4] = 1;
+        assert!(matches!(
+            decode(&image, &mut eval, None),
+            Err(ImageError::Version(1))
+        ));
+    }
+
+    #[test]
+    fn rejects_zero_denominators() {
+        let mut image = image("(define x 2/3)");
+        let at = image
+            .windows(5)
+            .position(|w| w == b"\x01\x00\x00\x003")
+            .unwrap();
+        image[at + 4] = b'0';
+        let sum = checksum(&image[HEADER..]);
+        image[16..HEADER].copy_from_slice(&sum.to_le_bytes());
+        assert!(matches!(
+            decode(&image, &mut Evaluator::new(), None),
+            Err(ImageError::Malformed(what)) if what == "number"
+        ));
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+ * Ctrl-C in the REPL. The handler only sets a flag, the evaluator checks it at every step
+ * and gives up with an error, so the session goes on with its definitions intact.
+ * At the prompt there is nothing to interrupt, so it prompts again with how to leave instead.
+ */
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static WAITING: AtomicBool = AtomicBool::new(false); // For input at the prompt
+
+// Written by the handler itself, the terminal already dropped the line being typed
+const HINT: &str = "\n(press Ctrl-D to leave)\nlinked> ";
+
+const SIGINT: i32 = 2;
+
+unsafe extern "C" {
+    // From the C library, the previous handler it returns isn't needed
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+}
+
+extern "C" fn handle(_: i32) {
+    if WAITING.load(Ordering::Relaxed) {
+        unsafe {
+            write(1, HINT.as_ptr(), HINT.len());
+        }
+    } else {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/* Makes Ctrl-C interrupt the evaluation instead of ending the program. */
+pub fn install() {
+    unsafe {
+        signal(SIGINT, handle);
+    }
+}
+
+/* Whether Ctrl-C was pressed since the last time, which it then forgets. */
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed) && INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+/* Acts as if Ctrl-C was pressed during an evaluation. */
+#[cfg(test)]
+pub fn press() {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/* Tells whether the REPL waits at its prompt, and forgets any Ctrl-C pressed until then. */
+pub fn waiting(waiting: bool) {
+    WAITING.store(waiting, Ordering::Relaxed);
+    INTERRUPTED.store(false, Ordering::Relaxed);
+}
@@ -1,35 +1,175 @@
+#[cfg(test)]
+mod bench;
+mod compiler;
+mod conditions;
 mod evaluator;
+mod gc;
+mod heap;
+mod image;
+mod interrupt;
+mod maps;
+mod math;
+mod number;
 mod parser;
 mod printer;
+mod records;
 mod scanner;
+mod specializer;
+mod stack;
+mod strings;
+mod symbols;
 mod types;
+mod vectors;
+mod vm;
 
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::process;
 
-fn repl() {
+fn repl(mut eval: evaluator::Evaluator, mut machine: Option<vm::Machine>) {
     println!("Welcome to Linked Lisp! Have fun lisping!");
+    interrupt::install();
+    eval.interruptible = true;
 
     loop {
         let mut input = String::new();
 
         print!("linked> ");
         io::stdout().flush().unwrap();
-        io::stdin()
+        interrupt::waiting(true);
+        let read = io::stdin()
             .read_line(&mut input)
             .expect("Linked: Failed to read input!\n");
-        printer::print(evaluator::evaluate(parser::parse(scanner::scan(&input))));
+        interrupt::waiting(false);
+        // Ctrl-D or the end of piped input
+        if read == 0 {
+            println!();
+            return;
+        }
+        let expressions = match parser::read(scanner::scan(&input)) {
+            Ok(expressions) => expressions,
+            Err(err) => {
+                println!("Linked: {}", err);
+                continue;
+            }
+        };
+        let result = match machine.as_mut() {
+            Some(machine) => machine.run(expressions, &mut eval),
+            None => evaluator::evaluate(expressions, &mut eval),
+        };
+        match result {
+            Ok(e) => printer::print(&e),
+            Err(err) => print!("Linked: {}", err),
+        }
         println!();
     }
 }
 
+/* Reads the expressions of a program, a program that can't be read ends the run. */
+fn read(source: &str) -> Vec<types::Expression> {
+    match parser::read(scanner::scan(source)) {
+        Ok(expressions) => expressions,
+        Err(err) => {
+            println!("Linked: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/* Loads a program and prints the residual program of specialising one of its functions. */
+fn specialize(mut eval: evaluator::Evaluator, path: &str, function: &str, args: &[String]) {
+    let contents = fs::read_to_string(path).expect("Linked: Error reading the file!\n");
+    let call = format!("(specialize {} {})", function, args.join(" "));
+
+    let result = evaluator::evaluate(read(&contents), &mut eval)
+        .and_then(|_| evaluator::evaluate(read(&call), &mut eval));
+    match result {
+        Ok(types::Expression::List(program)) => {
+            for e in program.iter() {
+                printer::print(e);
+                println!();
+            }
+        }
+        Ok(e) => {
+            printer::print(&e);
+            println!();
+        }
+        Err(err) => {
+            println!("Linked: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/* Runs a module on the virtual machine and writes an image of the globals it defines. */
+fn compile(mut eval: evaluator::Evaluator, path: &str, image_path: &str) {
+    let contents = fs::read_to_string(path).expect("Linked: Error reading the file!\n");
+    let mut machine = vm::Machine::new();
+    if let Err(err) = machine.run(read(&contents), &mut eval) {
+        println!("Linked: {}", err);
+        process::exit(1);
+    }
+    if let Err(err) = image::save(image_path, &eval, &machine) {
+        println!("Linked: {}: {}", image_path, err);
+        process::exit(1);
+    }
+}
+
+/* The number given to a flag like `--fuel=`. */
+fn number(arg: &str, flag: &str) -> usize {
+    match arg[flag.len()..].parse() {
+        Ok(n) => n,
+        Err(_) => {
+            println!("Linked: {} expects a number!", &flag[..flag.len() - 1]);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    stack::run(start);
+}
+
+fn start() {
+    let mut eval = evaluator::Evaluator::new();
+    let mut machine = None;
+    let mut images = Vec::new();
+    let mut args: Vec<String> = Vec::new();
+    for arg in env::args() {
+        match arg.as_str() {
+            "--curry" => eval.curry = true,
+            "--strict" => eval.strict = true,
+            "--vm" => machine = Some(vm::Machine::new()),
+            a if a.starts_with("--load=") => images.push(a["--load=".len()..].to_string()),
+            a if a.starts_with("--unfold=") => eval.unfold_budget = number(a, "--unfold="),
+            a if a.starts_with("--fuel=") => eval.fuel = Some(number(a, "--fuel=")),
+            a if a.starts_with("--max-depth=") => eval.max_depth = Some(number(a, "--max-depth=")),
+            a if a.starts_with("--max-heap=") => eval.max_heap = Some(number(a, "--max-heap=")),
+            _ => args.push(arg),
+        }
+    }
+
+    for path in images {
+        if let Err(err) = image::load(&path, &mut eval, machine.as_mut()) {
+            println!("Linked: {}: {}", path, err);
+            process::exit(1);
+        }
+    }
 
-    if args.len() > 2 {
-        println!("Usage: linked [path]");
+    if args.len() >= 4 && args[1] == "--specialize" {
+        specialize(eval, &args[2], &args[3], &args[4..]);
+    } else if args.len() == 4 && args[1] == "--compile" {
+        compile(eval, &args[2], &args[3]);
+    } else if args.len() > 2 {
+        println!(
+            "Usage: linked [--curry] [--strict] [--unfold=budget] [--vm] [--load=image...] [path]"
+        );
+        println!("       with limits [--fuel=steps] [--max-depth=calls] [--max-heap=bytes]");
+        println!(
+            "       linked [--curry] [--strict] [--unfold=budget] --specialize path function [args...]"
+        );
+        println!("       linked --compile path image");
         process::exit(1);
     } else if args.len() == 2 {
         let contents =
@@ -38,6 +178,6 @@ fn main() {
         println!("{:?}", scanner::scan(&contents));
     // RUN PARSE HERE!
     } else {
-        repl();
+        repl(eval, machine);
     }
 }
@@ -0,0 +1,210 @@
+use crate::gc;
+use crate::types::{Expression, LispError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Names of the hash map builtins, `hash-for-each` is handled by the evaluator since it calls functions
+pub const FUNCTIONS: &[&str] = &[
+    "hash",
+    "hash-ref",
+    "hash-set!",
+    "hash-remove!",
+    "hash-has-key?",
+    "hash-count",
+    "hash-keys",
+    "hash-values",
+    "hash->alist",
+    "hash-for-each",
+];
+
+/* Hash table keeping its entries in insertion order, so it prints and iterates predictably. */
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Expression, Expression)>,
+    index: HashMap<Expression, usize>,
+}
+
+impl Map {
+    pub fn get(&self, key: &Expression) -> Option<&Expression> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn insert(&mut self, key: Expression, value: Expression) {
+        match self.index.get(&key) {
+            Some(i) => self.entries[*i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &Expression) {
+        if let Some(i) = self.index.remove(key) {
+            self.entries.remove(i);
+            for (k, _) in self.entries[i..].iter() {
+                *self.index.get_mut(k).unwrap() -= 1;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> &[(Expression, Expression)] {
+        &self.entries
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        self.len() == other.len()
+            && self
+                .entries
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+/* Checks if a value can be used as a key, mutable values can't since their hash would change. */
+pub fn is_key(e: &Expression) -> bool {
+    match e {
+        Expression::List(v) => v.iter().all(is_key),
+        Expression::Quote(e) => is_key(e),
+        Expression::Vector(_)
+        | Expression::Map(_)
+        | Expression::Record(_)
+        | Expression::Lambda(..)
+        | Expression::Procedure(_) => false,
+        _ => true,
+    }
+}
+
+/* The smallest and largest number of arguments of a builtin, None if there is no limit. */
+pub fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "hash" => (0, None),
+        "hash-ref" => (2, Some(3)),
+        "hash-set!" => (3, Some(3)),
+        "hash-remove!" | "hash-has-key?" | "hash-for-each" => (2, Some(2)),
+        _ => (1, Some(1)),
+    }
+}
+
+/* Builds a map from alternating keys and values. */
+pub fn new(pairs: &[Expression], call: &Expression) -> Result<Expression, LispError> {
+    if !pairs.len().is_multiple_of(2) {
+        return Err(LispError::Arity(call.clone(), "an even number".to_string()));
+    }
+    let mut map = Map::default();
+    for pair in pairs.chunks(2) {
+        map.insert(key(&pair[0], call)?, pair[1].clone());
+    }
+    Ok(from(map))
+}
+
+pub fn from(map: Map) -> Expression {
+    let map = Expression::Map(Rc::new(RefCell::new(map)));
+    gc::track_value(&map);
+    map
+}
+
+pub fn map(e: &Expression, call: &Expression) -> Result<Rc<RefCell<Map>>, LispError> {
+    match e {
+        Expression::Map(m) => Ok(m.clone()),
+        _ => Err(LispError::Type(call.clone(), "a hash map".to_string())),
+    }
+}
+
+fn key(e: &Expression, call: &Expression) -> Result<Expression, LispError> {
+    if is_key(e) {
+        Ok(e.clone())
+    } else {
+        Err(LispError::Type(call.clone(), "a hashable key".to_string()))
+    }
+}
+
+/*
+ * Applies the hash map builtin `name` to fully known arguments, the arity must already be checked.
+ * `call` is the whole call, used in errors.
+ */
+pub fn call(name: &str, args: &[Expression], call: &Expression) -> Result<Expression, LispError> {
+    if name == "hash" {
+        return new(args, call);
+    }
+    let m = map(&args[0], call)?;
+    let ret = match name {
+        "hash-ref" => match (m.borrow().get(&key(&args[1], call)?), args.get(2)) {
+            (Some(value), _) => value.clone(),
+            (None, Some(default)) => default.clone(),
+            (None, None) => {
+                return Err(LispError::Range(
+                    call.clone(),
+                    "a key in the map".to_string(),
+                ));
+            }
+        },
+        "hash-set!" => {
+            let k = key(&args[1], call)?;
+            m.borrow_mut().insert(k, args[2].clone());
+            Expression::Nil
+        }
+        "hash-remove!" => {
+            let k = key(&args[1], call)?;
+            m.borrow_mut().remove(&k);
+            Expression::Nil
+        }
+        "hash-has-key?" => Expression::Boolean(m.borrow().get(&key(&args[1], call)?).is_some()),
+        "hash-count" => Expression::Number((m.borrow().len() as i64).into()),
+        "hash-keys" => Expression::list_or_nil(
+            m.borrow()
+                .entries()
+                .iter()
+                .map(|(k, _)| k.clone())
+                .collect(),
+        ),
+        "hash-values" => Expression::list_or_nil(
+            m.borrow()
+                .entries()
+                .iter()
+                .map(|(_, v)| v.clone())
+                .collect(),
+        ),
+        "hash->alist" => Expression::list_or_nil(
+            m.borrow()
+                .entries()
+                .iter()
+                .map(|(k, v)| Expression::list(vec![k.clone(), v.clone()]))
+                .collect(),
+        ),
+        _ => call.clone(),
+    };
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::{self, Evaluator};
+    use crate::{parser, scanner};
+
+    fn run(program: &str, eval: &mut Evaluator) -> String {
+        let expressions = parser::parse(scanner::scan(program));
+        evaluator::evaluate(expressions, eval).unwrap().to_string()
+    }
+
+    #[test]
+    fn maps_holding_themselves() {
+        let mut eval = Evaluator::new();
+        run("(define h (hash)) (hash-set! h :self h)", &mut eval);
+        assert_eq!(run("(hash-count h)", &mut eval), "1");
+        assert_eq!(run("(hash-count (hash-ref h :self))", &mut eval), "1");
+        assert_eq!(run("h", &mut eval), "{:self #<cycle>}");
+        // The same map twice side by side isn't a cycle
+        assert_eq!(
+            run("(vector h h)", &mut eval),
+            "#({:self #<cycle>} {:self #<cycle>})"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::number::Number;
+use std::cmp::Ordering;
+
+// Names of the math builtins handled by `call`
+pub const FUNCTIONS: &[&str] = &[
+    "expt",
+    "sqrt",
+    "exp",
+    "log",
+    "sin",
+    "cos",
+    "tan",
+    "asin",
+    "acos",
+    "atan",
+    "abs",
+    "min",
+    "max",
+    "floor",
+    "round",
+    "mod",
+    "quotient",
+    "remainder",
+    "%",
+];
+
+// Constants bound in the global environment
+pub const CONSTANTS: &[(&str, f64)] = &[("pi", std::f64::consts::PI), ("e", std::f64::consts::E)];
+
+fn real(x: &Number, f: fn(f64) -> f64) -> Number {
+    Number::Real(f(x.to_f64()))
+}
+
+/*
+ * Picks the smallest (Less) or largest (Greater) of the numbers.
+ * Like in Scheme, the result is inexact if any of the arguments is.
+ */
+pub fn extreme(numbers: &[Number], wanted: Ordering) -> Number {
+    let mut best = numbers[0].clone();
+    for n in numbers[1..].iter() {
+        if n.num_cmp(&best) == Some(wanted) {
+            best = n.clone();
+        }
+    }
+    if numbers.iter().all(|n| n.is_exact()) {
+        best
+    } else {
+        Number::Real(best.to_f64())
+    }
+}
+
+/* The smallest and largest number of arguments of a builtin, None if there is no limit. */
+pub fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "expt" | "mod" | "quotient" | "remainder" | "%" => (2, Some(2)),
+        "log" | "atan" => (1, Some(2)),
+        "min" | "max" => (1, None),
+        _ => (1, Some(1)),
+    }
+}
+
+/* About how many bytes the result of the builtin takes when it can be huge, zero otherwise. */
+pub fn allocates(name: &str, args: &[Number]) -> usize {
+    match (name, args) {
+        ("expt", [base, exp]) => base.expt_bytes(exp),
+        _ => 0,
+    }
+}
+
+/* Checks if the builtin would divide an exact number by exact zero. */
+pub fn divides_by_zero(name: &str, args: &[Number]) -> bool {
+    match (name, args) {
+        ("mod" | "quotient" | "remainder" | "%", [_, d]) => d.is_exact() && d.is_zero(),
+        ("expt", [base, Number::Integer(exp)]) => {
+            base.is_exact() && base.is_zero() && exp.is_negative()
+        }
+        _ => false,
+    }
+}
+
+/*
+ * Applies the math builtin `name` to fully numeric arguments.
+ * Returns None for a wrong arity, or integer division of numbers that aren't integers.
+ */
+pub fn call(name: &str, args: &[Number]) -> Option<Number> {
+    match (name, args) {
+        ("expt", [base, exp]) => base.expt(exp),
+        ("sqrt", [x]) => Some(x.sqrt()),
+        ("exp", [x]) => Some(real(x, f64::exp)),
+        ("log", [x]) => Some(real(x, f64::ln)),
+        ("log", [x, base]) => Some(Number::Real(x.to_f64().log(base.to_f64()))),
+        ("sin", [x]) => Some(real(x, f64::sin)),
+        ("cos", [x]) => Some(real(x, f64::cos)),
+        ("tan", [x]) => Some(real(x, f64::tan)),
+        ("asin", [x]) => Some(real(x, f64::asin)),
+        ("acos", [x]) => Some(real(x, f64::acos)),
+        ("atan", [x]) => Some(real(x, f64::atan)),
+        ("atan", [y, x]) => Some(Number::Real(y.to_f64().atan2(x.to_f64()))),
+        ("abs", [x]) => Some(x.abs()),
+        ("min", [_, ..]) => Some(extreme(args, Ordering::Less)),
+        ("max", [_, ..]) => Some(extreme(args, Ordering::Greater)),
+        ("floor", [x]) => Some(x.floor()),
+        ("round", [x]) => Some(x.round()),
+        ("mod", [a, b]) => a.modulo(b),
+        ("quotient", [a, b]) => a.quotient(b),
+        ("remainder" | "%", [a, b]) => a.remainder(b),
+        _ => None,
+    }
+}
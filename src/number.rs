@@ -0,0 +1,888 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/*
+ * Arbitrary-precision integer.
+ * The magnitude is stored as little-endian base 2^32 limbs with no trailing zero limbs,
+ * so zero is the empty vector and is never negative.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+// Largest power of ten that fits in a limb, used for decimal conversions.
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_DIGITS: usize = 9;
+
+fn trim(v: &mut Vec<u32>) {
+    while v.last() == Some(&0) {
+        v.pop();
+    }
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, x) in long.iter().enumerate() {
+        let s = *x as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        out.push(s as u32);
+        carry = s >> 32;
+    }
+    if carry != 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+/* Subtracts the magnitudes, a must be greater than or equal to b. */
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, x) in a.iter().enumerate() {
+        let mut d = *x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        if d < 0 {
+            d += 1 << 32;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+        out.push(d as u32);
+    }
+    trim(&mut out);
+    out
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let t = *x as u64 * *y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    trim(&mut out);
+    out
+}
+
+/* Computes a * m + c in place. */
+fn mul_add_small(a: &mut Vec<u32>, m: u32, c: u32) {
+    let mut carry = c as u64;
+    for x in a.iter_mut() {
+        let t = *x as u64 * m as u64 + carry;
+        *x = t as u32;
+        carry = t >> 32;
+    }
+    if carry != 0 {
+        a.push(carry as u32);
+    }
+}
+
+fn divmod_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut q = vec![0u32; a.len()];
+    let mut r = 0u64;
+    for i in (0..a.len()).rev() {
+        let cur = (r << 32) | a[i] as u64;
+        q[i] = (cur / d as u64) as u32;
+        r = cur % d as u64;
+    }
+    trim(&mut q);
+    (q, r as u32)
+}
+
+fn shl1(v: &mut Vec<u32>, bit: u32) {
+    let mut carry = bit;
+    for x in v.iter_mut() {
+        let next = *x >> 31;
+        *x = (*x << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        v.push(carry);
+    }
+}
+
+/* Long division on magnitudes, b must not be zero. */
+fn divmod_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_mag(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if b.len() == 1 {
+        let (q, r) = divmod_small(a, b[0]);
+        return (q, if r == 0 { Vec::new() } else { vec![r] });
+    }
+    // Plain shift-and-subtract, one bit of quotient at a time
+    let mut q = vec![0u32; a.len()];
+    let mut r: Vec<u32> = Vec::new();
+    for i in (0..a.len() * 32).rev() {
+        shl1(&mut r, (a[i / 32] >> (i % 32)) & 1);
+        if cmp_mag(&r, b) != Ordering::Less {
+            r = sub_mag(&r, b);
+            q[i / 32] |= 1 << (i % 32);
+        }
+    }
+    trim(&mut q);
+    (q, r)
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, mut limbs: Vec<u32>) -> BigInt {
+        trim(&mut limbs);
+        BigInt {
+            negative: negative && !limbs.is_empty(),
+            limbs,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_one(&self) -> bool {
+        !self.negative && self.limbs == [1]
+    }
+
+    /* The number of bits of the magnitude, zero for zero. */
+    pub fn bits(&self) -> usize {
+        match self.limbs.last() {
+            Some(top) => 32 * self.limbs.len() - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    /* Parses an optionally signed string of decimal digits. */
+    pub fn parse(s: &str) -> Option<BigInt> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut limbs = Vec::new();
+        for chunk in digits.as_bytes().chunks(DECIMAL_DIGITS) {
+            let value: u32 = std::str::from_utf8(chunk).ok()?.parse().ok()?;
+            mul_add_small(&mut limbs, 10u32.pow(chunk.len() as u32), value);
+        }
+        Some(BigInt::from_parts(negative, limbs))
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+        let mag = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0u64, |acc, l| (acc << 32) | *l as u64);
+        if self.negative {
+            0i64.checked_sub_unsigned(mag)
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mag = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, l| acc * 4294967296.0 + *l as f64);
+        if self.negative { -mag } else { mag }
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.limbs.clone())
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::from_parts(false, self.limbs.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_mag(&self.limbs, &other.limbs));
+        }
+        match cmp_mag(&self.limbs, &other.limbs) {
+            Ordering::Less => {
+                BigInt::from_parts(other.negative, sub_mag(&other.limbs, &self.limbs))
+            }
+            _ => BigInt::from_parts(self.negative, sub_mag(&self.limbs, &other.limbs)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(
+            self.negative != other.negative,
+            mul_mag(&self.limbs, &other.limbs),
+        )
+    }
+
+    /* Truncating division, the remainder takes the sign of the dividend. */
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = divmod_mag(&self.limbs, &other.limbs);
+        Some((
+            BigInt::from_parts(self.negative != other.negative, q),
+            BigInt::from_parts(self.negative, r),
+        ))
+    }
+
+    /* Floor division, the remainder takes the sign of the divisor. */
+    pub fn div_mod_floor(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        let (q, r) = self.div_rem(other)?;
+        if !r.is_zero() && r.is_negative() != other.is_negative() {
+            Some((q.sub(&BigInt::from(1)), r.add(other)))
+        } else {
+            Some((q, r))
+        }
+    }
+
+    pub fn pow(&self, mut exp: u64) -> BigInt {
+        let mut base = self.clone();
+        let mut acc = BigInt::from(1);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = acc.mul(&base);
+            }
+            base = base.mul(&base);
+            exp >>= 1;
+        }
+        acc
+    }
+
+    /* Integer square root of a non-negative number using Newton's method. */
+    pub fn isqrt(&self) -> BigInt {
+        if self.is_zero() {
+            return self.clone();
+        }
+        let bits = self.limbs.len() * 32 - self.limbs.last().unwrap().leading_zeros() as usize;
+        // Start from a power of two above the root and descend
+        let k = bits / 2 + 1;
+        let mut limbs = vec![0u32; k / 32 + 1];
+        limbs[k / 32] = 1 << (k % 32);
+        let mut x = BigInt::from_parts(false, limbs);
+        loop {
+            let (q, _) = self.div_rem(&x).unwrap();
+            let (y, _) = x.add(&q).div_rem(&BigInt::from(2)).unwrap();
+            if y >= x {
+                return x;
+            }
+            x = y;
+        }
+    }
+
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let mut a = self.abs();
+        let mut b = other.abs();
+        while !b.is_zero() {
+            let (_, r) = a.div_rem(&b).unwrap();
+            a = b;
+            b = r;
+        }
+        a
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> BigInt {
+        let mag = n.unsigned_abs();
+        BigInt::from_parts(n < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.limbs, &other.limbs),
+            (true, true) => cmp_mag(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut chunks = Vec::new();
+        let mut mag = self.limbs.clone();
+        while !mag.is_empty() {
+            let (q, r) = divmod_small(&mag, DECIMAL_BASE);
+            chunks.push(r);
+            mag = q;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The numeric tower.
+ * Integers and rationals are exact, a rational is always reduced with a denominator greater than one.
+ * Reals are inexact and only come from literals with a decimal point or exponent, or inexact operations.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Integer(BigInt),
+    Rational(BigInt, BigInt),
+    Real(f64),
+}
+
+impl Number {
+    /* Builds a normalised exact ratio, returns None when the denominator is zero. */
+    pub fn rational(n: BigInt, d: BigInt) -> Option<Number> {
+        if d.is_zero() {
+            return None;
+        }
+        let g = n.gcd(&d);
+        let (mut n, _) = n.div_rem(&g)?;
+        let (mut d, _) = d.div_rem(&g)?;
+        if d.is_negative() {
+            n = n.neg();
+            d = d.neg();
+        }
+        if d.is_one() {
+            Some(Number::Integer(n))
+        } else {
+            Some(Number::Rational(n, d))
+        }
+    }
+
+    /* Parses integer (`12`), ratio (`1/3`), decimal (`1.5`, `2e10`) and special (`+inf.0`) literals. */
+    pub fn parse(s: &str) -> Option<Number> {
+        match s {
+            "+inf.0" => return Some(Number::Real(f64::INFINITY)),
+            "-inf.0" => return Some(Number::Real(f64::NEG_INFINITY)),
+            "+nan.0" | "-nan.0" => return Some(Number::Real(f64::NAN)),
+            _ => (),
+        }
+        if let Some(n) = BigInt::parse(s) {
+            return Some(Number::Integer(n));
+        }
+        if let Some((n, d)) = s.split_once('/') {
+            if d.starts_with(['+', '-']) {
+                return None;
+            }
+            return Number::rational(BigInt::parse(n)?, BigInt::parse(d)?);
+        }
+        let is_decimal = s.contains(['.', 'e', 'E'])
+            && s.bytes().any(|b| b.is_ascii_digit())
+            && s.bytes()
+                .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b));
+        if is_decimal {
+            s.parse::<f64>().ok().map(Number::Real)
+        } else {
+            None
+        }
+    }
+
+    /* Checks for a ratio literal with a zero denominator, like `1/0`, which has no value. */
+    pub fn is_ratio_by_zero(s: &str) -> bool {
+        match s.split_once('/') {
+            Some((n, d)) => {
+                !d.starts_with(['+', '-'])
+                    && BigInt::parse(n).is_some()
+                    && BigInt::parse(d).is_some_and(|d| d.is_zero())
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Real(_))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(n) => n.is_zero(),
+            Number::Rational(_, _) => false,
+            Number::Real(f) => *f == 0.0,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(n) => n.to_f64(),
+            Number::Rational(n, d) => n.to_f64() / d.to_f64(),
+            Number::Real(f) => *f,
+        }
+    }
+
+    /* Returns the exact value as a numerator/denominator pair. */
+    fn ratio(&self) -> Option<(BigInt, BigInt)> {
+        match self {
+            Number::Integer(n) => Some((n.clone(), BigInt::from(1))),
+            Number::Rational(n, d) => Some((n.clone(), d.clone())),
+            Number::Real(_) => None,
+        }
+    }
+
+    pub fn neg(&self) -> Number {
+        match self {
+            Number::Integer(n) => Number::Integer(n.neg()),
+            Number::Rational(n, d) => Number::Rational(n.neg(), d.clone()),
+            Number::Real(f) => Number::Real(-f),
+        }
+    }
+
+    pub fn add(&self, other: &Number) -> Number {
+        match (self.ratio(), other.ratio()) {
+            (Some((n1, d1)), Some((n2, d2))) => {
+                Number::rational(n1.mul(&d2).add(&n2.mul(&d1)), d1.mul(&d2)).unwrap()
+            }
+            _ => Number::Real(self.to_f64() + other.to_f64()),
+        }
+    }
+
+    pub fn sub(&self, other: &Number) -> Number {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Number) -> Number {
+        match (self.ratio(), other.ratio()) {
+            (Some((n1, d1)), Some((n2, d2))) => Number::rational(n1.mul(&n2), d1.mul(&d2)).unwrap(),
+            _ => Number::Real(self.to_f64() * other.to_f64()),
+        }
+    }
+
+    /* Exact division by exact zero is undefined and returns None, inexact division follows IEEE. */
+    pub fn div(&self, other: &Number) -> Option<Number> {
+        match (self.ratio(), other.ratio()) {
+            (Some((n1, d1)), Some((n2, d2))) => Number::rational(n1.mul(&d2), d1.mul(&n2)),
+            _ => Some(Number::Real(self.to_f64() / other.to_f64())),
+        }
+    }
+
+    /* Numeric comparison across exactness, so `1` and `1.0` compare as equal. */
+    pub fn num_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (self.ratio(), other.ratio()) {
+            (Some((n1, d1)), Some((n2, d2))) => Some(n1.mul(&d2).cmp(&n2.mul(&d1))),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+
+    pub fn num_eq(&self, other: &Number) -> bool {
+        self.num_cmp(other) == Some(Ordering::Equal)
+    }
+
+    pub fn abs(&self) -> Number {
+        match self {
+            Number::Integer(n) => Number::Integer(n.abs()),
+            Number::Rational(n, d) => Number::Rational(n.abs(), d.clone()),
+            Number::Real(f) => Number::Real(f.abs()),
+        }
+    }
+
+    pub fn floor(&self) -> Number {
+        match self {
+            Number::Integer(_) => self.clone(),
+            Number::Rational(n, d) => Number::Integer(n.div_mod_floor(d).unwrap().0),
+            Number::Real(f) => Number::Real(f.floor()),
+        }
+    }
+
+    /* Rounds to the nearest integer, ties go to the even neighbour. */
+    pub fn round(&self) -> Number {
+        match self {
+            Number::Integer(_) => self.clone(),
+            Number::Rational(n, d) => {
+                let (fl, rem) = n.div_mod_floor(d).unwrap();
+                let up = fl.add(&BigInt::from(1));
+                match rem.mul(&BigInt::from(2)).cmp(d) {
+                    Ordering::Less => Number::Integer(fl),
+                    Ordering::Greater => Number::Integer(up),
+                    Ordering::Equal => {
+                        let (_, parity) = fl.div_rem(&BigInt::from(2)).unwrap();
+                        Number::Integer(if parity.is_zero() { fl } else { up })
+                    }
+                }
+            }
+            Number::Real(f) => Number::Real(f.round_ties_even()),
+        }
+    }
+
+    /*
+     * Integer division operators, only defined on integral values.
+     * Exact integers stay exact, integral reals give a real result.
+     */
+    fn integer_division(
+        &self,
+        other: &Number,
+        exact: fn(&BigInt, &BigInt) -> Option<BigInt>,
+        inexact: fn(f64, f64) -> f64,
+    ) -> Option<Number> {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => exact(a, b).map(Number::Integer),
+            // Inexact, so dividing by zero follows IEEE like `/` does
+            _ if self.is_integral() && other.is_integral() => {
+                Some(Number::Real(inexact(self.to_f64(), other.to_f64())))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn quotient(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, |a, b| Some(a.div_rem(b)?.0), |a, b| (a / b).trunc())
+    }
+
+    pub fn remainder(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, |a, b| Some(a.div_rem(b)?.1), |a, b| a % b)
+    }
+
+    pub fn modulo(&self, other: &Number) -> Option<Number> {
+        self.integer_division(
+            other,
+            |a, b| Some(a.div_mod_floor(b)?.1),
+            |a, b| a - b * (a / b).floor(),
+        )
+    }
+
+    pub fn is_integral(&self) -> bool {
+        match self {
+            Number::Integer(_) => true,
+            Number::Rational(_, _) => false,
+            Number::Real(f) => f.fract() == 0.0,
+        }
+    }
+
+    /* Raising an exact number to an exact integer power stays exact. */
+    pub fn expt(&self, exp: &Number) -> Option<Number> {
+        if let (Some((n, d)), Number::Integer(e)) = (self.ratio(), exp)
+            && let Some(e) = e.to_i64()
+        {
+            let (n, d) = (n.pow(e.unsigned_abs()), d.pow(e.unsigned_abs()));
+            return if e < 0 {
+                Number::rational(d, n)
+            } else {
+                Number::rational(n, d)
+            };
+        }
+        Some(Number::Real(self.to_f64().powf(exp.to_f64())))
+    }
+
+    /* About how many bytes the result of `expt` takes, so a huge power can be refused beforehand. */
+    pub fn expt_bytes(&self, exp: &Number) -> usize {
+        match (self.ratio(), exp) {
+            (Some((n, d)), Number::Integer(e)) => {
+                let e = e.to_i64().map_or(usize::MAX, |e| e.unsigned_abs() as usize);
+                (n.bits() + d.bits()).saturating_sub(2).saturating_mul(e) / 8
+            }
+            _ => 0,
+        }
+    }
+
+    /* The square root of an exact perfect square is exact, anything else is a real. */
+    pub fn sqrt(&self) -> Number {
+        if let Some((n, d)) = self.ratio()
+            && !n.is_negative()
+        {
+            let (rn, rd) = (n.isqrt(), d.isqrt());
+            if rn.mul(&rn) == n && rd.mul(&rd) == d {
+                return Number::rational(rn, rd).unwrap();
+            }
+        }
+        Number::Real(self.to_f64().sqrt())
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Number {
+        Number::Integer(BigInt::from(n))
+    }
+}
+
+/* Hashes consistently with the derived equality, so numbers can be hash map keys. */
+impl Hash for Number {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Number::Integer(n) => n.hash(state),
+            Number::Rational(n, d) => {
+                n.hash(state);
+                d.hash(state);
+            }
+            // 0.0 and -0.0 are equal but have different bits
+            Number::Real(f) if *f == 0.0 => 0u64.hash(state),
+            Number::Real(f) => f.to_bits().hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Integer(n) => write!(f, "{}", n),
+            Number::Rational(n, d) => write!(f, "{}/{}", n, d),
+            Number::Real(r) if r.is_nan() => write!(f, "+nan.0"),
+            Number::Real(r) if r.is_infinite() => {
+                write!(f, "{}inf.0", if *r > 0.0 { "+" } else { "-" })
+            }
+            // Debug formatting always keeps the decimal point, so reals read back as reals
+            Number::Real(r) => write!(f, "{:?}", r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BigInt, Number};
+    use std::cmp::Ordering;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::parse(s).unwrap()
+    }
+
+    fn number(s: &str) -> Number {
+        Number::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_and_print() {
+        for s in [
+            "0",
+            "7",
+            "-7",
+            "999999999",
+            "1000000000",
+            "-1000000000",
+            "1000000000000000000",
+            "4294967296",
+            "123456789012345678901234567890",
+            "-98765432109876543210000000000000000001",
+        ] {
+            assert_eq!(big(s).to_string(), s);
+        }
+        assert_eq!(big("-0").to_string(), "0");
+        assert!(!big("-0").is_negative());
+        assert_eq!(big("+42").to_string(), "42");
+        assert_eq!(big("000123").to_string(), "123");
+        for s in ["", "-", "+", "1a", "1.0", "--1"] {
+            assert_eq!(BigInt::parse(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn carry_and_borrow_across_limbs() {
+        let limb = big("4294967296");
+        assert_eq!(big("4294967295").add(&big("1")), limb);
+        assert_eq!(limb.sub(&big("1")).to_string(), "4294967295");
+        let two_limbs = big("18446744073709551616");
+        assert_eq!(two_limbs.sub(&big("1")).to_string(), "18446744073709551615");
+        assert_eq!(big("18446744073709551615").add(&big("1")), two_limbs);
+        assert_eq!(
+            big("18446744073709551615")
+                .mul(&big("18446744073709551615"))
+                .to_string(),
+            "340282366920938463426481119284349108225"
+        );
+        assert_eq!(
+            big("4294967297").mul(&big("4294967295")).to_string(),
+            "18446744073709551615"
+        );
+    }
+
+    #[test]
+    fn signs() {
+        assert_eq!(big("-5").add(&big("3")).to_string(), "-2");
+        assert_eq!(big("5").add(&big("-8")).to_string(), "-3");
+        assert_eq!(big("-5").sub(&big("-5")), big("0"));
+        assert!(!big("5").add(&big("-5")).is_negative());
+        assert_eq!(big("-3").mul(&big("4")).to_string(), "-12");
+        assert_eq!(big("-3").mul(&big("-4")).to_string(), "12");
+        assert!(!big("-3").mul(&big("0")).is_negative());
+        assert_eq!(big("-4294967296").abs(), big("4294967296"));
+        assert_eq!(big("-2").cmp(&big("1")), Ordering::Less);
+        assert_eq!(big("-2").cmp(&big("-3")), Ordering::Greater);
+        assert_eq!(big("-4294967296").cmp(&big("-1")), Ordering::Less);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(BigInt::from(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(BigInt::from(i64::MAX).to_i64(), Some(i64::MAX));
+        assert_eq!(BigInt::from(i64::MIN).to_string(), "-9223372036854775808");
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+        assert_eq!(big("18446744073709551616").to_f64(), 18446744073709551616.0);
+    }
+
+    #[test]
+    fn division() {
+        let cases = [
+            ("7", "2"),
+            ("-7", "2"),
+            ("7", "-2"),
+            ("-7", "-2"),
+            ("79228162514264337593543950341", "18446744073709551619"),
+            ("-79228162514264337593543950341", "4294967296"),
+            ("1000000000000000000000000000007", "1000000000000000"),
+            ("18446744073709551615", "18446744073709551616"),
+            (
+                "340282366920938463463374607431768211455",
+                "-18446744073709551617",
+            ),
+        ];
+        for (a, b) in cases {
+            let (a, b) = (big(a), big(b));
+            let (q, r) = a.div_rem(&b).unwrap();
+            assert_eq!(q.mul(&b).add(&r), a, "{} / {}", a, b);
+            assert!(r.abs() < b.abs(), "{} / {}", a, b);
+            assert!(r.is_zero() || r.is_negative() == a.is_negative());
+            let (q, m) = a.div_mod_floor(&b).unwrap();
+            assert_eq!(q.mul(&b).add(&m), a, "{} mod {}", a, b);
+            assert!(m.is_zero() || m.is_negative() == b.is_negative());
+        }
+        assert_eq!(
+            big("79228162514264337593543950336").div_rem(&big("18446744073709551616")),
+            Some((big("4294967296"), big("0")))
+        );
+        assert_eq!(big("-7").div_rem(&big("2")), Some((big("-3"), big("-1"))));
+        assert_eq!(
+            big("-7").div_mod_floor(&big("2")),
+            Some((big("-4"), big("1")))
+        );
+        assert_eq!(
+            big("7").div_mod_floor(&big("-2")),
+            Some((big("-4"), big("-1")))
+        );
+        assert_eq!(big("1").div_rem(&big("0")), None);
+    }
+
+    #[test]
+    fn roots_powers_and_gcd() {
+        for (n, root) in [
+            ("0", "0"),
+            ("1", "1"),
+            ("3", "1"),
+            ("4", "2"),
+            ("15", "3"),
+            ("16", "4"),
+            ("18446744073709551616", "4294967296"),
+            ("18446744073709551615", "4294967295"),
+            (
+                "100000000000000000000000000000000000000000",
+                "316227766016837933199",
+            ),
+        ] {
+            assert_eq!(big(n).isqrt(), big(root), "isqrt {}", n);
+        }
+        assert_eq!(big("2").pow(64), big("18446744073709551616"));
+        assert_eq!(big("-3").pow(3), big("-27"));
+        assert_eq!(big("5").pow(0), big("1"));
+        assert_eq!(big("0").bits(), 0);
+        assert_eq!(big("-1").bits(), 1);
+        assert_eq!(big("18446744073709551616").bits(), 65);
+        assert_eq!(number("1").expt_bytes(&number("1000000000000")), 0);
+        assert_eq!(number("2").expt_bytes(&number("8000")), 1000);
+        assert_eq!(number("1/2").expt_bytes(&number("-8000")), 1000);
+        assert_eq!(number("2.0").expt_bytes(&number("8000")), 0);
+        assert_eq!(big("0").gcd(&big("5")), big("5"));
+        assert_eq!(big("-12").gcd(&big("18")), big("6"));
+        let a = big("18446744073709551616").mul(&big("35"));
+        assert_eq!(
+            a.gcd(&big("-4294967296").mul(&big("21"))),
+            big("30064771072")
+        );
+    }
+
+    #[test]
+    fn rationals() {
+        assert_eq!(number("2/4").to_string(), "1/2");
+        assert_eq!(number("-6/3"), Number::from(-2));
+        assert_eq!(
+            Number::rational(BigInt::from(1), BigInt::from(-2))
+                .unwrap()
+                .to_string(),
+            "-1/2"
+        );
+        assert_eq!(Number::parse("1/-3"), None);
+        assert_eq!(Number::parse("1/0"), None);
+        assert!(Number::is_ratio_by_zero("1/0"));
+        assert!(Number::is_ratio_by_zero("-5/000"));
+        assert!(!Number::is_ratio_by_zero("0/5"));
+        assert!(!Number::is_ratio_by_zero("a/0"));
+        assert_eq!(number("1/3").add(&number("1/6")).to_string(), "1/2");
+        assert_eq!(number("1/2").sub(&number("1/2")), Number::from(0));
+        assert_eq!(number("2/3").mul(&number("3/2")), Number::from(1));
+        assert_eq!(number("1/2").div(&number("0")), None);
+        assert_eq!(number("2").expt(&number("-2")), Some(number("1/4")));
+        assert_eq!(number("-2/3").expt(&number("3")), Some(number("-8/27")));
+        assert_eq!(number("9/4").sqrt(), number("3/2"));
+        assert_eq!(number("2").sqrt(), Number::Real(2f64.sqrt()));
+        assert_eq!(number("1/3").num_cmp(&number("0.5")), Some(Ordering::Less));
+        assert!(number("1").num_eq(&number("1.0")));
+    }
+
+    #[test]
+    fn rounding() {
+        for (n, rounded) in [
+            ("1/2", "0"),
+            ("3/2", "2"),
+            ("5/2", "2"),
+            ("7/2", "4"),
+            ("-1/2", "0"),
+            ("-3/2", "-2"),
+            ("-5/2", "-2"),
+            ("7/3", "2"),
+            ("-7/3", "-2"),
+            ("8/3", "3"),
+        ] {
+            assert_eq!(number(n).round().to_string(), rounded, "round {}", n);
+        }
+        assert_eq!(number("2.5").round(), number("2.0"));
+        assert_eq!(number("-1/2").floor(), Number::from(-1));
+        assert_eq!(number("7/2").floor(), Number::from(3));
+    }
+
+    #[test]
+    fn reals() {
+        assert_eq!(number("1.0").to_string(), "1.0");
+        assert_eq!(number("1e3").to_string(), "1000.0");
+        assert_eq!(number("-inf.0").to_string(), "-inf.0");
+        assert_eq!(number("+nan.0").to_string(), "+nan.0");
+        assert_eq!(number("7.5").quotient(&number("2")), None);
+        assert_eq!(number("-7.0").modulo(&number("2")), Some(number("1.0")));
+    }
+}
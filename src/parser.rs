@@ -1,6 +1,22 @@
-use crate::types;
+use crate::maps::{self, Map};
+use crate::number::Number;
+use crate::symbols::Symbol;
+use crate::types::{self, CHAR_NAMES};
+use crate::vectors;
+use std::fmt;
+use std::rc::Rc;
 use types::Expression;
 
+// A literal that has no value, like the ratio `1/0`, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadError(String, String);
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot read {}, {}", self.0, self.1)
+    }
+}
+
 struct Parser {
     // Need an Expression here
     index: usize,         // Where it is currently!
@@ -20,50 +36,103 @@ impl Parser {
     }
 }
 
-fn is_number(s: &str) -> bool {
-    if s.is_empty() {
-        return false;
-    }
-
-    s.parse::<f64>().is_ok()
-}
-
-fn parse_list(tokens: &Vec<String>, parser: &mut Parser) -> Expression {
+/* Parses the elements up to the closing token, which is consumed too. */
+fn parse_elements(
+    tokens: &Vec<String>,
+    parser: &mut Parser,
+    close: &str,
+) -> Result<Vec<Expression>, ReadError> {
     let mut l: Vec<Expression> = Vec::new();
-    while parser.index < tokens.len() && tokens[parser.index].as_str() != ")" {
-        l.push(parse_expression(tokens, parser));
+    while parser.index < tokens.len() && tokens[parser.index].as_str() != close {
+        l.push(parse_expression(tokens, parser)?);
     }
     parser.index += 1;
+    Ok(l)
+}
+
+fn parse_list(tokens: &Vec<String>, parser: &mut Parser) -> Result<Expression, ReadError> {
+    let l = parse_elements(tokens, parser, ")")?;
     if l.is_empty() || parser.index > tokens.len() {
-        Expression::Nil
+        Ok(Expression::Nil)
     } else {
-        Expression::List(l)
+        Ok(Expression::list(l))
     }
 }
 
-fn parse_expression(tokens: &Vec<String>, parser: &mut Parser) -> Expression {
+/*
+ * Checks if a token is an identifier in the R7RS syntax.
+ * Besides names starting with a letter or one of `! $ % & * / : < = > ? ^ _ ~`, this allows `+`, `-`, `...`
+ * and names like `->x` or `.foo` that can't be mistaken for numbers.
+ */
+pub fn is_identifier(s: &str) -> bool {
+    let initial = |c: char| c.is_alphabetic() || "!$%&*/:<=>?^_~".contains(c);
+    let subsequent = |c: char| initial(c) || c.is_numeric() || "+-.@".contains(c);
+    let dot_subsequent = |c: char| initial(c) || "+-@.".contains(c);
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if initial(c) => chars.all(subsequent),
+        Some('+' | '-') => match chars.next() {
+            None => true,
+            Some('.') => chars.next().is_some_and(dot_subsequent) && chars.all(subsequent),
+            Some(c) => (initial(c) || "+-@".contains(c)) && chars.all(subsequent),
+        },
+        Some('.') => chars.next().is_some_and(dot_subsequent) && chars.all(subsequent),
+        _ => false,
+    }
+}
+
+fn parse_expression(tokens: &Vec<String>, parser: &mut Parser) -> Result<Expression, ReadError> {
     if parser.index >= tokens.len() {
-        return Expression::Nil;
+        return Ok(Expression::Nil);
     }
 
     let input = tokens[parser.index].as_str();
-    match input {
+    let e = match input {
         // Parse a LIST here
         "(" => {
             parser.index += 1;
-            parse_list(tokens, parser)
+            parse_list(tokens, parser)?
+        }
+        // Parse a vector literal here, its elements are constants and aren't evaluated
+        "[" => {
+            parser.index += 1;
+            vectors::new(parse_elements(tokens, parser, "]")?)
+        }
+        "#" if tokens.get(parser.index + 1).is_some_and(|t| t == "(") => {
+            parser.index += 2;
+            vectors::new(parse_elements(tokens, parser, ")")?)
+        }
+        // Parse a hash map literal here, alternating constant keys and values
+        "{" => {
+            parser.index += 1;
+            let pairs = parse_elements(tokens, parser, "}")?;
+            let literal = || {
+                let elements: Vec<String> = pairs.iter().map(|e| e.to_string()).collect();
+                format!("{{{}}}", elements.join(" "))
+            };
+            if !pairs.len().is_multiple_of(2) {
+                return Err(ReadError(literal(), "a key has no value".to_string()));
+            }
+            let mut map = Map::default();
+            for pair in pairs.chunks(2) {
+                if !maps::is_key(&pair[0]) {
+                    return Err(ReadError(literal(), format!("{} can't be a key", pair[0])));
+                }
+                map.insert(pair[0].clone(), pair[1].clone());
+            }
+            maps::from(map)
         }
         // Parse a quote here
         "'" => {
             parser.index += 1;
-            let e = Expression::Quote(Box::new(parse_expression(tokens, parser)));
-            e
+
+            Expression::quote(parse_expression(tokens, parser)?)
         }
         // Parse an unquote here
         "," => {
             parser.index += 1;
-            let e = Expression::Unquote(Box::new(parse_expression(tokens, parser)));
-            e
+
+            Expression::Unquote(Rc::new(parse_expression(tokens, parser)?))
         }
         // Parse a boolean here
         "true" | "t" => {
@@ -79,51 +148,143 @@ fn parse_expression(tokens: &Vec<String>, parser: &mut Parser) -> Expression {
 
         "nil" => {
             parser.index += 1;
-            let e = Expression::Nil;
-            e
+
+            Expression::Nil
         }
         // Parse a string here!
         s if s.starts_with('\"') && s.ends_with('\"') => {
             parser.index += 1;
             let text = &s[1..s.len() - 1];
-            let e = Expression::String(text.to_string());
-            e
+
+            Expression::String(text.to_string())
+        }
+        // Parse a character here!
+        s if s.starts_with("#\\") => {
+            parser.index += 1;
+            let name = &s[2..];
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Expression::Char(c),
+                _ => match CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+                    Some((_, c)) => Expression::Char(*c),
+                    None => {
+                        return Err(ReadError(s.to_string(), "no such character".to_string()));
+                    }
+                },
+            }
         }
         // Parse a number here!
-        s if is_number(s) => {
+        s if let Some(num) = Number::parse(s) => {
             parser.index += 1;
-            let num = s.parse::<f64>().unwrap();
-            let e = Expression::Number(num);
-            e
+            Expression::Number(num)
+        }
+        s if Number::is_ratio_by_zero(s) => {
+            return Err(ReadError(s.to_string(), "division by zero".to_string()));
+        }
+        // Parse the markers used in parameter lists here!
+        s if s == "." || s.starts_with("#:") => {
+            parser.index += 1;
+            Expression::Symbol(Symbol::new(s))
+        }
+        // Parse a quoted symbol here!
+        s if s.len() >= 2 && s.starts_with('|') && s.ends_with('|') => {
+            parser.index += 1;
+            Expression::Symbol(Symbol::new(&s[1..s.len() - 1]))
+        }
+        // Parse a keyword here!
+        s if s.len() > 1 && s.starts_with(':') && is_identifier(&s[1..]) => {
+            parser.index += 1;
+            Expression::Keyword(s[1..].to_string())
         }
         // Parse a symbol here!
-        s if (s.chars().next().is_some_and(|c| c.is_alphabetic())
-            && s.chars().all(|c| c.is_alphanumeric()))
-            || (s.len() == 1 && "+-*/%=".contains(s)) =>
-        {
+        s if is_identifier(s) => {
             parser.index += 1;
-            let e = Expression::Symbol(s.to_string());
-            e
+
+            Expression::Symbol(Symbol::new(s))
         }
         _ => {
             parser.index += 1;
-            let e = Expression::Nil;
-            e
+
+            Expression::Nil
         }
-    }
+    };
+    Ok(e)
 }
 
-pub fn parse(tokens: Vec<String>) -> Vec<Expression> {
+/* Reads the expressions of a program, or the first token that can't be read. */
+pub fn read(tokens: Vec<String>) -> Result<Vec<Expression>, ReadError> {
     if tokens.is_empty() {
-        return vec![Expression::Nil];
+        return Ok(vec![Expression::Nil]);
     }
 
     let mut parser = Parser::new();
 
     while parser.index < tokens.len() {
-        let e = parse_expression(&tokens, &mut parser);
+        let e = parse_expression(&tokens, &mut parser)?;
         parser.push_expr(e);
     }
 
-    return parser.asf;
+    Ok(parser.asf)
+}
+
+/* Parses a program known to be well formed, for the tests. */
+#[cfg(test)]
+pub fn parse(tokens: Vec<String>) -> Vec<Expression> {
+    read(tokens).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_identifier, parse, read};
+    use crate::scanner::scan;
+    use crate::symbols::Symbol;
+    use crate::types::Expression;
+
+    #[test]
+    fn rejects_ratios_by_zero() {
+        let err = read(scan("(+ 1 1/0)")).unwrap_err();
+        assert_eq!(err.to_string(), "cannot read 1/0, division by zero");
+        assert_eq!(parse(scan("(+ 1 0/1)")).len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_character_names() {
+        let err = read(scan("(list #\\a #\\bell)")).unwrap_err();
+        assert_eq!(err.to_string(), "cannot read #\\bell, no such character");
+        assert_eq!(parse(scan("(list #\\space #\\x)")).len(), 1);
+    }
+
+    #[test]
+    fn rejects_odd_maps() {
+        let err = read(scan("{:a 1 :b}")).unwrap_err();
+        assert_eq!(err.to_string(), "cannot read {:a 1 :b}, a key has no value");
+        let err = read(scan("{[1] 2}")).unwrap_err();
+        assert_eq!(err.to_string(), "cannot read {#(1) 2}, #(1) can't be a key");
+        assert_eq!(parse(scan("{:a 1 (1 2) 2}")).len(), 1);
+    }
+
+    #[test]
+    fn identifiers() {
+        for s in [
+            "list->vector",
+            "<=",
+            "+",
+            "-",
+            "...",
+            "->x",
+            ".foo",
+            "+a",
+            "a.b",
+            "set!",
+        ] {
+            assert!(is_identifier(s), "{}", s);
+        }
+        for s in ["1+", "+1", "-.5", ".", "a b", "#a"] {
+            assert!(!is_identifier(s), "{}", s);
+        }
+        let symbols = parse(scan("(list->vector <= |a b| |b|)"));
+        let expected =
+            ["list->vector", "<=", "a b", "b"].map(|s| Expression::Symbol(Symbol::new(s)));
+        assert_eq!(symbols, vec![Expression::list(expected.to_vec())]);
+    }
 }
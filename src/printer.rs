@@ -1,23 +1,138 @@
-use crate::types::Expression;
+use crate::types::{CHAR_NAMES, Expression, Params};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 
-pub fn print(e: Expression) {
-    match e {
-        Expression::Symbol(s) => print!("{}", s),
-        Expression::Number(n) => print!("{}", n),
-        Expression::String(s) => print!("\"{}\"", s),
-        Expression::Boolean(b) => print!("{}", b),
-        Expression::List(v) => {
-            print!("{}", '(');
-            for (i, el) in v.iter().enumerate() {
-                print(el.clone());
-                // Only print a space if it's not the last element
-                if i < v.len() - 1 {
-                    print!(" ");
+thread_local! {
+    // The vectors, maps and records being written, innermost last
+    static PRINTING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+/* Writes a vector, map or record with `write`, or `#<cycle>` if it's already being written. */
+fn guarded(
+    f: &mut fmt::Formatter,
+    container: *const (),
+    write: impl FnOnce(&mut fmt::Formatter) -> fmt::Result,
+) -> fmt::Result {
+    if PRINTING.with(|printing| printing.borrow().contains(&container)) {
+        return write!(f, "#<cycle>");
+    }
+    PRINTING.with(|printing| printing.borrow_mut().push(container));
+    let result = write(f);
+    PRINTING.with(|printing| printing.borrow_mut().pop());
+    result
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A lone rest parameter is written as a bare symbol, like `(lambda args ...)`
+        if self.required.is_empty()
+            && self.optional.is_empty()
+            && self.key.is_empty()
+            && let Some(rest) = &self.rest
+        {
+            return write!(f, "{}", rest);
+        }
+        let mut parts: Vec<String> = self.required.iter().map(|s| s.to_string()).collect();
+        if !self.optional.is_empty() {
+            parts.push("#:optional".to_string());
+            for (name, default) in self.optional.iter() {
+                match default {
+                    Expression::Nil => parts.push(name.to_string()),
+                    _ => parts.push(format!("({} {})", name, default)),
+                }
+            }
+        }
+        if !self.key.is_empty() {
+            parts.push("#:key".to_string());
+            for (name, default) in self.key.iter() {
+                match default {
+                    Expression::Nil => parts.push(name.to_string()),
+                    _ => parts.push(format!("({} {})", name, default)),
+                }
+            }
+        }
+        if let Some(rest) = &self.rest {
+            parts.push(format!(". {}", rest));
+        }
+        write!(f, "({})", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Symbols that wouldn't scan as one token are written between bars
+            Expression::Symbol(s) | Expression::Local(s, ..)
+                if s.name().is_empty()
+                    || s.name()
+                        .contains(|c: char| c.is_whitespace() || "()[]{}\"';,|".contains(c)) =>
+            {
+                write!(f, "|{}|", s)
+            }
+            Expression::Symbol(s) | Expression::Local(s, ..) => write!(f, "{}", s),
+            Expression::Number(n) => write!(f, "{}", n),
+            // Written so it reads back as the same string
+            Expression::String(s) => {
+                write!(f, "\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Expression::Char(c) => match CHAR_NAMES.iter().find(|(_, named)| named == c) {
+                Some((name, _)) => write!(f, "#\\{}", name),
+                None => write!(f, "#\\{}", c),
+            },
+            Expression::Keyword(k) => write!(f, ":{}", k),
+            Expression::Boolean(b) => write!(f, "{}", b),
+            Expression::Quote(e) => write!(f, "'{}", e),
+            Expression::Unquote(e) => write!(f, ",{}", e),
+            Expression::List(v) => {
+                write!(f, "(")?;
+                for (i, el) in v.iter().enumerate() {
+                    write!(f, "{}", el)?;
+                    // Only print a space if it's not the last element
+                    if i < v.len() - 1 {
+                        write!(f, " ")?;
+                    }
                 }
+                write!(f, ")")
             }
-            print!("{}", ')');
+            Expression::Vector(v) => guarded(f, Rc::as_ptr(v) as *const (), |f| {
+                let v = v.borrow();
+                let elements: Vec<String> = v.iter().map(|e| e.to_string()).collect();
+                write!(f, "#({})", elements.join(" "))
+            }),
+            Expression::Map(m) => guarded(f, Rc::as_ptr(m) as *const (), |f| {
+                let m = m.borrow();
+                let entries: Vec<String> = m
+                    .entries()
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(" "))
+            }),
+            Expression::Record(r) => guarded(f, Rc::as_ptr(r) as *const (), |f| {
+                write!(f, "#<{}", r.kind.name)?;
+                for (field, value) in r.kind.fields.iter().zip(r.values.borrow().iter()) {
+                    write!(f, " {}={}", field, value)?;
+                }
+                write!(f, ">")
+            }),
+            Expression::Lambda(params, body, _) => write!(f, "(lambda {} {})", params, body),
+            // Like a builtin, by its name
+            Expression::Procedure(p) => write!(f, "{}", Expression::Symbol(p.name)),
+            Expression::Nil => write!(f, "nil"),
         }
-        Expression::Nil => print!("nil"),
-        _ => println!("Well that was weird..."),
     }
 }
+
+/* Writes a value the way `display` shows it, strings and characters without their quoting. */
+pub fn display(e: &Expression) -> String {
+    match e {
+        Expression::String(s) => s.clone(),
+        Expression::Char(c) => c.to_string(),
+        _ => e.to_string(),
+    }
+}
+
+pub fn print(e: &Expression) {
+    print!("{}", e);
+}
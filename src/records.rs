@@ -0,0 +1,171 @@
+use crate::gc;
+use crate::symbols::Symbol;
+use crate::types::{Expression, LispError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/*
+ * Record types and their procedures. Each `define-record-type` makes a new type, and only its
+ * constructor, predicate, accessors and modifiers make and use its instances, so a record can't be
+ * forged or changed by anything else, not even through another type of the same name.
+ */
+
+// A record type, told apart from the others by its identity and not its name
+#[derive(Debug)]
+pub struct RecordType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl PartialEq for RecordType {
+    fn eq(&self, other: &RecordType) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// An instance of a record type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: Rc<RecordType>,
+    pub values: RefCell<Vec<Expression>>,
+}
+
+// What a procedure of a record type does
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    Make(Vec<usize>), // The fields its arguments fill, the others start out as nil
+    Is,
+    Ref(usize),
+    Set(usize),
+}
+
+// A procedure of a record type, named after what it is bound to
+#[derive(Debug, PartialEq)]
+pub struct Procedure {
+    pub name: Symbol,
+    pub kind: Rc<RecordType>,
+    pub access: Access,
+}
+
+impl Procedure {
+    /* How many arguments the procedure takes. */
+    pub fn arity(&self) -> usize {
+        match &self.access {
+            Access::Make(fields) => fields.len(),
+            Access::Is | Access::Ref(_) => 1,
+            Access::Set(_) => 2,
+        }
+    }
+}
+
+/*
+ * Builds the procedures of `(define-record-type name (constructor field...) predicate (field accessor [modifier])...)`.
+ * Returns the names to bind and their procedures, or None if the definition is malformed.
+ */
+pub fn define(vexp: &[Expression]) -> Option<Vec<(Symbol, Expression)>> {
+    let [
+        _,
+        Expression::Symbol(name),
+        Expression::List(constructor),
+        Expression::Symbol(predicate),
+        specs @ ..,
+    ] = vexp
+    else {
+        return None;
+    };
+    let mut fields = Vec::new();
+    let mut accesses = Vec::new();
+    for spec in specs {
+        let Expression::List(spec) = spec else {
+            return None;
+        };
+        let names: Option<Vec<&str>> = spec
+            .iter()
+            .map(|e| match e {
+                Expression::Symbol(s) => Some(s.name()),
+                _ => None,
+            })
+            .collect();
+        match names?.as_slice() {
+            [field, rest @ ..] if rest.len() <= 2 => {
+                if let Some(accessor) = rest.first() {
+                    accesses.push((Symbol::new(accessor), Access::Ref(fields.len())));
+                }
+                if let Some(modifier) = rest.get(1) {
+                    accesses.push((Symbol::new(modifier), Access::Set(fields.len())));
+                }
+                fields.push(field.to_string());
+            }
+            _ => return None,
+        }
+    }
+
+    // The constructor's arguments fill their fields, the other fields start out as nil
+    let Some((Expression::Symbol(make), args)) = constructor.split_first() else {
+        return None;
+    };
+    let mut filled = Vec::new();
+    for arg in args {
+        match arg {
+            Expression::Symbol(s) => match fields.iter().position(|f| f == s.name()) {
+                Some(i) if !filled.contains(&i) => filled.push(i),
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+    accesses.insert(0, (*make, Access::Make(filled)));
+    accesses.insert(1, (*predicate, Access::Is));
+
+    let kind = Rc::new(RecordType {
+        name: name.name().to_string(),
+        fields,
+    });
+    let procedures = accesses
+        .into_iter()
+        .map(|(name, access)| {
+            let kind = kind.clone();
+            let procedure = Procedure { name, kind, access };
+            (name, Expression::Procedure(Rc::new(procedure)))
+        })
+        .collect();
+    Some(procedures)
+}
+
+/*
+ * Applies a procedure of a record type to fully known arguments, the arity must already be checked.
+ * `call` is the whole call, used in errors.
+ */
+pub fn call(
+    procedure: &Procedure,
+    args: &[Expression],
+    call: &Expression,
+) -> Result<Expression, LispError> {
+    let kind = &procedure.kind;
+    let instance = match args.first() {
+        Some(Expression::Record(r)) if r.kind == *kind => Some(r),
+        _ => None,
+    };
+    let ret = match (&procedure.access, instance) {
+        (Access::Make(filled), _) => {
+            let mut values = vec![Expression::Nil; kind.fields.len()];
+            for (i, arg) in filled.iter().zip(args) {
+                values[*i] = arg.clone();
+            }
+            let record = Expression::Record(Rc::new(Record {
+                kind: kind.clone(),
+                values: RefCell::new(values),
+            }));
+            gc::track_value(&record);
+            record
+        }
+        (Access::Is, instance) => Expression::Boolean(instance.is_some()),
+        (Access::Ref(i), Some(r)) => r.values.borrow()[*i].clone(),
+        (Access::Set(i), Some(r)) => {
+            r.values.borrow_mut()[*i] = args[1].clone();
+            Expression::Nil
+        }
+        _ => return Err(LispError::Type(call.clone(), format!("a {}", kind.name))),
+    };
+    Ok(ret)
+}
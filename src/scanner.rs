@@ -1,9 +1,11 @@
 // All the possible scan states
 enum ScanState {
-    NORMAL,
-    STRING,
-    BACKSLASH,
-    COMMENT,
+    Normal,
+    String,
+    Backslash,
+    Comment,
+    Char,
+    Pipe,
 }
 
 // Scanner structure
@@ -16,7 +18,7 @@ struct Scanner {
 impl Scanner {
     fn new() -> Scanner {
         Scanner {
-            state: ScanState::NORMAL,
+            state: ScanState::Normal,
             tokens: Vec::new(),
             current: String::new(),
         }
@@ -37,27 +39,21 @@ impl Scanner {
     }
 }
 
-/* Checks if a given char should be treated as a single character lexeme. */
+/*
+ * Checks if a given char should be treated as a single character lexeme.
+ * Operators like `*` and `=` aren't, identifiers such as `*global*` or `string=?` may contain them.
+ */
 fn is_single(c: char) -> bool {
-    match c {
-        '(' | ')' | '/' | '*' | '%' | '\'' | ',' | '=' | '\\' => true,
-        _ => false,
-    }
+    matches!(c, '(' | ')' | '[' | ']' | '{' | '}' | '\'' | ',' | '\\')
 }
 
 fn is_prefix(c: char) -> bool {
-    match c {
-        '+' | '-' | '.' => true,
-        _ => false,
-    }
+    matches!(c, '+' | '-' | '.')
 }
 
 /* Checks if a given char is a whitespace character. */
 fn is_whitespace(c: char) -> bool {
-    match c {
-        ' ' | '\n' | '\t' | '\r' => true,
-        _ => false,
-    }
+    matches!(c, ' ' | '\n' | '\t' | '\r')
 }
 
 /*
@@ -70,8 +66,11 @@ pub fn scan(input: &str) -> Vec<String> {
     for c in input.chars() {
         match scanner.state {
             /* When in NORMAL, add words as tokens and switch to STRING or COMMENT if necessary */
-            ScanState::NORMAL => {
-                if is_single(c) {
+            ScanState::Normal => {
+                if c == '\\' && scanner.current == "#" {
+                    scanner.push_char(c);
+                    scanner.state = ScanState::Char;
+                } else if is_single(c) {
                     scanner.flush();
                     scanner.push_token(c.to_string());
                 } else if is_prefix(c) && scanner.current.is_empty() {
@@ -79,9 +78,13 @@ pub fn scan(input: &str) -> Vec<String> {
                 } else if c == '\"' {
                     scanner.flush();
                     scanner.push_char(c);
-                    scanner.state = ScanState::STRING;
+                    scanner.state = ScanState::String;
+                } else if c == '|' {
+                    scanner.flush();
+                    scanner.push_char(c);
+                    scanner.state = ScanState::Pipe;
                 } else if c == ';' {
-                    scanner.state = ScanState::COMMENT;
+                    scanner.state = ScanState::Comment;
                 } else if is_whitespace(c) {
                     scanner.flush();
                 } else {
@@ -89,19 +92,19 @@ pub fn scan(input: &str) -> Vec<String> {
                 }
             }
             /* When in STRING, add sentence as a token and switch to BACKSLASH or NORMAL if necessary */
-            ScanState::STRING => {
+            ScanState::String => {
                 if c == '\"' {
                     scanner.push_char(c);
                     scanner.flush();
-                    scanner.state = ScanState::NORMAL;
+                    scanner.state = ScanState::Normal;
                 } else if c == '\\' {
-                    scanner.state = ScanState::BACKSLASH;
+                    scanner.state = ScanState::Backslash;
                 } else {
                     scanner.push_char(c);
                 }
             }
             /* When in BACKSLASH, process the next char as a special and switch back to STRING */
-            ScanState::BACKSLASH => {
+            ScanState::Backslash => {
                 match c {
                     'n' => scanner.push_char('\n'),
                     't' => scanner.push_char('\t'),
@@ -113,12 +116,25 @@ pub fn scan(input: &str) -> Vec<String> {
                         scanner.push_char(c);
                     }
                 };
-                scanner.state = ScanState::STRING;
+                scanner.state = ScanState::String;
+            }
+            /* When in PIPE, add the quoted symbol as a token and switch back to NORMAL at the closing bar */
+            ScanState::Pipe => {
+                scanner.push_char(c);
+                if c == '|' {
+                    scanner.flush();
+                    scanner.state = ScanState::Normal;
+                }
+            }
+            /* When in CHAR, take the next char whatever it is as the character and switch back to NORMAL */
+            ScanState::Char => {
+                scanner.push_char(c);
+                scanner.state = ScanState::Normal;
             }
             /* When in COMMENT, ignore input until we reach the newline then switch to NORMAL */
-            ScanState::COMMENT => {
+            ScanState::Comment => {
                 if c == '\n' {
-                    scanner.state = ScanState::NORMAL;
+                    scanner.state = ScanState::Normal;
                 }
             }
         }
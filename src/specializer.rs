@@ -0,0 +1,170 @@
+use crate::symbols::{self, Symbol};
+use crate::types::{Environment, Expression, Params};
+use std::collections::HashMap;
+
+// How deeply calls may be unfolded before they are left to the original function
+pub const MAX_UNFOLD_DEPTH: usize = 200;
+// How many variants of one function are made before its arguments are all treated as dynamic
+pub const MAX_VARIANTS: usize = 8;
+
+/*
+ * Checks if a value is fully known, that is it doesn't contain any free symbol.
+ * Vectors, maps and records are only made once the code runs, so they are known whatever they hold,
+ * and they aren't looked into, which could take long or never end for one that holds itself.
+ */
+pub fn is_static(e: &Expression) -> bool {
+    match e {
+        Expression::Symbol(_) | Expression::Local(..) => false,
+        Expression::List(v) => v.iter().all(is_static),
+        _ => true,
+    }
+}
+
+// A specialised variant of a function, for one combination of static arguments
+struct Variant {
+    function: Symbol,
+    key: Vec<Option<Expression>>, // The static arguments, None for the dynamic ones
+    name: Symbol,
+    definition: Option<Expression>, // Filled in once the body has been specialised
+}
+
+/*
+ * State of an offline specialisation run.
+ * Calls under dynamic control are memoised as variants, so recursion over dynamic data ends up as residual calls.
+ */
+pub struct Specializer {
+    variants: Vec<Variant>,
+    counts: HashMap<Symbol, usize>,
+    pub dynamic: usize, // How many undecided conds we are inside of
+    pub depth: usize,   // How many calls are being unfolded
+}
+
+/* Builds `(define (name params...) body)`. */
+pub fn definition(name: Symbol, params: &Params, body: Expression) -> Expression {
+    let mut header = vec![Expression::Symbol(name)];
+    header.extend(params.required.iter().cloned().map(Expression::Symbol));
+    for (marker, group) in [
+        (symbols::OPTIONAL, &params.optional),
+        (symbols::KEY, &params.key),
+    ] {
+        if !group.is_empty() {
+            header.push(Expression::Symbol(marker));
+            for (param, default) in group.iter() {
+                header.push(Expression::list(vec![
+                    Expression::Symbol(*param),
+                    default.clone(),
+                ]));
+            }
+        }
+    }
+    if let Some(rest) = &params.rest {
+        header.push(Expression::Symbol(symbols::DOT));
+        header.push(Expression::Symbol(*rest));
+    }
+    Expression::list(vec![
+        Expression::Symbol(symbols::DEFINE),
+        Expression::list(header),
+        body,
+    ])
+}
+
+/* Collects the global functions referenced by an expression. */
+fn referenced_functions(e: &Expression, env: &Environment, found: &mut Vec<Symbol>) {
+    match e {
+        Expression::Symbol(s) if !found.contains(s) => {
+            if let Expression::Lambda(_, body, _) = env.get(*s) {
+                found.push(*s);
+                referenced_functions(&body, env, found);
+            }
+        }
+        Expression::List(v) => v.iter().for_each(|e| referenced_functions(e, env, found)),
+        Expression::Lambda(_, body, _) => referenced_functions(body, env, found),
+        _ => (),
+    }
+}
+
+impl Specializer {
+    pub fn new() -> Specializer {
+        Specializer {
+            variants: Vec::new(),
+            counts: HashMap::new(),
+            dynamic: 0,
+            depth: 0,
+        }
+    }
+
+    /*
+     * Finds the variant for a call, or reserves a fresh name for a new one.
+     * Returns the name and whether the variant still has to be specialised.
+     * Once a function has too many variants, every argument is generalised to dynamic.
+     */
+    pub fn variant(
+        &mut self,
+        function: Symbol,
+        key: Vec<Option<Expression>>,
+        env: &Environment,
+    ) -> (Symbol, Vec<Option<Expression>>, bool) {
+        let find = |key: &Vec<Option<Expression>>| {
+            self.variants
+                .iter()
+                .find(|v| v.function == function && &v.key == key)
+                .map(|v| v.name)
+        };
+        let count = self.counts.get(&function).copied().unwrap_or(0);
+        let key = if find(&key).is_none() && count >= MAX_VARIANTS {
+            vec![None; key.len()]
+        } else {
+            key
+        };
+        if let Some(name) = find(&key) {
+            return (name, key, false);
+        }
+        self.reserve(function, key, count, env)
+    }
+
+    fn reserve(
+        &mut self,
+        function: Symbol,
+        key: Vec<Option<Expression>>,
+        count: usize,
+        env: &Environment,
+    ) -> (Symbol, Vec<Option<Expression>>, bool) {
+        let name = (count + 1..)
+            .map(|n| Symbol::new(&format!("{}{}", function, n)))
+            .find(|name| !env.is_defined(*name) && !self.variants.iter().any(|v| v.name == *name))
+            .unwrap();
+        self.counts.insert(function, count + 1);
+        self.variants.push(Variant {
+            function,
+            key: key.clone(),
+            name,
+            definition: None,
+        });
+        (name, key, true)
+    }
+
+    pub fn define(&mut self, name: Symbol, definition: Expression) {
+        if let Some(v) = self.variants.iter_mut().find(|v| v.name == name) {
+            v.definition = Some(definition);
+        }
+    }
+
+    /* The residual program: the variants in the order they were made, then the original functions they still call. */
+    pub fn program(self, env: &Environment) -> Vec<Expression> {
+        let mut program: Vec<Expression> = self
+            .variants
+            .into_iter()
+            .filter_map(|v| v.definition)
+            .collect();
+        let mut originals = Vec::new();
+        for e in program.iter() {
+            referenced_functions(e, env, &mut originals);
+        }
+        for function in originals {
+            if let Expression::Lambda(params, body, _) = env.get(function) {
+                program.push(definition(function, &params, (*body).clone()));
+            }
+        }
+        program
+    }
+}
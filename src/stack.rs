@@ -0,0 +1,37 @@
+use std::cell::Cell;
+use std::thread;
+
+/*
+ * The native stack the evaluator recurses on. Programs run on a thread with a stack of a known size,
+ * so recursing too deeply is a limit error while there is still room to report it, rather than
+ * an overflow ending the process.
+ */
+pub const SIZE: usize = 256 << 20;
+const RESERVE: usize = 16 << 20; // Left for unwinding, dropping and printing once the limit is reached
+
+thread_local! {
+    static BASE: Cell<usize> = const { Cell::new(0) }; // Where the stack starts, 0 on other threads
+}
+
+fn here() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/* Runs `f` on a new thread with a stack of `SIZE` bytes and waits for what it returns. */
+pub fn run<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    let thread = thread::Builder::new().stack_size(SIZE).spawn(move || {
+        BASE.set(here());
+        f()
+    });
+    match thread.expect("Linked: Failed to start!\n").join() {
+        Ok(ret) => ret,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+/* Whether the stack is nearly used up, never on a thread that wasn't started by `run`. */
+pub fn exhausted() -> bool {
+    let base = BASE.get();
+    base != 0 && base.saturating_sub(here()) > SIZE - RESERVE
+}
@@ -0,0 +1,250 @@
+use crate::number::Number;
+use crate::printer;
+use crate::symbols::Symbol;
+use crate::types::{Expression, LispError, check_arity, index};
+
+// Names of the string builtins handled by `call`
+pub const FUNCTIONS: &[&str] = &[
+    "string-length",
+    "substring",
+    "string-append",
+    "string-ref",
+    "string-split",
+    "string-join",
+    "string-upcase",
+    "string->number",
+    "number->string",
+    "string->symbol",
+    "symbol->string",
+    "string=?",
+    "string<?",
+    "format",
+];
+
+/* The smallest and largest number of arguments of a builtin, None if there is no limit. */
+fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "substring" => (2, Some(3)),
+        "string-ref" => (2, Some(2)),
+        "string-split" | "string-join" => (1, Some(2)),
+        "string-append" => (0, None),
+        "string=?" | "string<?" | "format" => (1, None),
+        _ => (1, Some(1)),
+    }
+}
+
+fn string<'a>(e: &'a Expression, call: &Expression) -> Result<&'a str, LispError> {
+    match e {
+        Expression::String(s) => Ok(s),
+        _ => Err(LispError::Type(call.clone(), "a string".to_string())),
+    }
+}
+
+fn strings<'a>(args: &'a [Expression], call: &Expression) -> Result<Vec<&'a str>, LispError> {
+    args.iter().map(|e| string(e, call)).collect()
+}
+
+/* About how many bytes the result of the builtin takes when it can be huge, zero otherwise. */
+pub fn allocates(name: &str, args: &[Expression]) -> usize {
+    match name {
+        "string-append" => args
+            .iter()
+            .map(|e| match e {
+                Expression::String(s) => s.len(),
+                _ => 0,
+            })
+            .fold(0, usize::saturating_add),
+        _ => 0,
+    }
+}
+
+/* Fails unless `i` is at most `max`. */
+fn in_range(i: usize, max: usize, call: &Expression) -> Result<usize, LispError> {
+    if i <= max {
+        Ok(i)
+    } else {
+        Err(LispError::Range(call.clone(), format!("at most {}", max)))
+    }
+}
+
+/*
+ * Fills in the directives of a format string: `~a` displays the next argument, `~s` writes it,
+ * `~%` is a newline and `~~` a tilde.
+ */
+fn format(template: &str, args: &[Expression], call: &Expression) -> Result<String, LispError> {
+    let mut used = 0;
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(d @ ('a' | 's')) => {
+                if let Some(arg) = args.get(used) {
+                    if d == 'a' {
+                        out.push_str(&printer::display(arg));
+                    } else {
+                        out.push_str(&arg.to_string());
+                    }
+                }
+                used += 1;
+            }
+            Some('%') => out.push('\n'),
+            Some('~') => out.push('~'),
+            _ => {
+                return Err(LispError::Type(
+                    call.clone(),
+                    "one of the directives ~a, ~s, ~% or ~~".to_string(),
+                ));
+            }
+        }
+    }
+    if used != args.len() {
+        return Err(LispError::Arity(call.clone(), format!("{}", used + 1)));
+    }
+    Ok(out)
+}
+
+/*
+ * Applies the string builtin `name` to fully known arguments.
+ * `call` is the whole call, used in errors.
+ */
+pub fn call(name: &str, args: &[Expression], call: &Expression) -> Result<Expression, LispError> {
+    let (min, max) = arity(name);
+    check_arity(min, max, args, || call.clone())?;
+
+    let ret = match name {
+        "string-length" => {
+            Expression::Number(Number::from(string(&args[0], call)?.chars().count() as i64))
+        }
+        "substring" => {
+            let chars: Vec<char> = string(&args[0], call)?.chars().collect();
+            let end = match args.get(2) {
+                Some(end) => in_range(index(end, call)?, chars.len(), call)?,
+                None => chars.len(),
+            };
+            let start = in_range(index(&args[1], call)?, end, call)?;
+            Expression::String(chars[start..end].iter().collect())
+        }
+        "string-append" => Expression::String(strings(args, call)?.concat()),
+        "string-ref" => {
+            let chars: Vec<char> = string(&args[0], call)?.chars().collect();
+            let i = index(&args[1], call)?;
+            match chars.get(i) {
+                Some(c) => Expression::Char(*c),
+                None => {
+                    return Err(LispError::Range(
+                        call.clone(),
+                        format!("below {}", chars.len()),
+                    ));
+                }
+            }
+        }
+        "string-split" => {
+            let s = string(&args[0], call)?;
+            // Without a separator the string is split on whitespace
+            let parts: Vec<String> = match args.get(1) {
+                None => s.split_whitespace().map(String::from).collect(),
+                Some(Expression::Char(c)) => s.split(*c).map(String::from).collect(),
+                Some(sep) => {
+                    let sep = string(sep, call)?;
+                    if sep.is_empty() {
+                        s.chars().map(String::from).collect()
+                    } else {
+                        s.split(sep).map(String::from).collect()
+                    }
+                }
+            };
+            Expression::list_or_nil(parts.into_iter().map(Expression::String).collect())
+        }
+        "string-join" => {
+            let Some(parts) = args[0].elements() else {
+                return Err(LispError::Type(
+                    call.clone(),
+                    "a list of strings".to_string(),
+                ));
+            };
+            let parts = strings(parts, call)?;
+            let sep = match args.get(1) {
+                Some(sep) => string(sep, call)?,
+                None => " ",
+            };
+            Expression::String(parts.join(sep))
+        }
+        "string-upcase" => Expression::String(string(&args[0], call)?.to_uppercase()),
+        "string->number" => match Number::parse(string(&args[0], call)?) {
+            Some(n) => Expression::Number(n),
+            None => Expression::Boolean(false),
+        },
+        "number->string" => match &args[0] {
+            Expression::Number(n) => Expression::String(n.to_string()),
+            _ => return Err(LispError::Type(call.clone(), "a number".to_string())),
+        },
+        "string->symbol" => {
+            Expression::quote(Expression::Symbol(Symbol::new(string(&args[0], call)?)))
+        }
+        "symbol->string" => match &args[0] {
+            Expression::Quote(e) if matches!(**e, Expression::Symbol(_)) => {
+                Expression::String(e.to_string())
+            }
+            _ => return Err(LispError::Type(call.clone(), "a symbol".to_string())),
+        },
+        "string=?" => {
+            let args = strings(args, call)?;
+            Expression::Boolean(args.windows(2).all(|w| w[0] == w[1]))
+        }
+        "string<?" => {
+            let args = strings(args, call)?;
+            Expression::Boolean(args.windows(2).all(|w| w[0] < w[1]))
+        }
+        "format" => Expression::String(format(string(&args[0], call)?, &args[1..], call)?),
+        _ => call.clone(),
+    };
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::call;
+    use crate::types::Expression;
+    use crate::{parser, scanner};
+
+    fn string(s: &str) -> Expression {
+        Expression::String(s.to_string())
+    }
+
+    fn run(name: &str, args: &[Expression]) -> Expression {
+        call(name, args, &Expression::Nil).unwrap()
+    }
+
+    #[test]
+    fn join_takes_quoted_lists() {
+        let parts = Expression::list(vec![string("a"), string("b")]);
+        assert_eq!(
+            run("string-join", &[Expression::quote(parts), string("-")]),
+            string("a-b")
+        );
+        assert_eq!(
+            run("string-join", &[Expression::quote(Expression::Nil)]),
+            string("")
+        );
+        assert!(call("string-join", &[string("a")], &Expression::Nil).is_err());
+    }
+
+    #[test]
+    fn written_strings_read_back() {
+        for s in ["q\"x", "back\\slash", "\\\"", "plain"] {
+            let Expression::String(written) = run("format", &[string("~s"), string(s)]) else {
+                panic!("format returned no string");
+            };
+            let read = parser::parse(scanner::scan(&written));
+            assert_eq!(read, vec![string(s)], "{}", written);
+        }
+        assert_eq!(
+            run("format", &[string("~a"), string("q\"x")]),
+            string("q\"x")
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+ * An interned name. Every name is stored once in a global table and symbols only carry its id,
+ * so they are copied and compared without touching the string.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// Names interned before any other, in the order of the ids below
+const PREDEFINED: &[&str] = &[
+    "define",
+    "cond",
+    "lambda",
+    "specialize",
+    "declare-symbolic",
+    "define-record-type",
+    "guard",
+    "+",
+    "-",
+    "*",
+    "/",
+    "=",
+    "#:optional",
+    "#:key",
+    ".",
+];
+
+// Special forms
+pub const DEFINE: Symbol = Symbol(0);
+pub const COND: Symbol = Symbol(1);
+pub const LAMBDA: Symbol = Symbol(2);
+pub const SPECIALIZE: Symbol = Symbol(3);
+pub const DECLARE_SYMBOLIC: Symbol = Symbol(4);
+pub const DEFINE_RECORD_TYPE: Symbol = Symbol(5);
+pub const GUARD: Symbol = Symbol(6);
+
+// Arithmetic the evaluator reduces itself
+pub const ADD: Symbol = Symbol(7);
+pub const SUBTRACT: Symbol = Symbol(8);
+pub const MULTIPLY: Symbol = Symbol(9);
+pub const DIVIDE: Symbol = Symbol(10);
+pub const EQUAL: Symbol = Symbol(11);
+
+// Markers in parameter lists
+pub const OPTIONAL: Symbol = Symbol(12);
+pub const KEY: Symbol = Symbol(13);
+pub const DOT: Symbol = Symbol(14);
+
+struct Table {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, Symbol>,
+}
+
+impl Table {
+    fn new() -> Table {
+        let mut table = Table {
+            names: Vec::new(),
+            ids: HashMap::new(),
+        };
+        for name in PREDEFINED {
+            table.insert(name);
+        }
+        table
+    }
+
+    fn insert(&mut self, name: &'static str) -> Symbol {
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(name);
+        self.ids.insert(name, symbol);
+        symbol
+    }
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new(Table::new());
+}
+
+impl Symbol {
+    /* Interns a name, the string of a new name is kept for as long as the program runs. */
+    pub fn new(name: &str) -> Symbol {
+        TABLE.with(|table| {
+            let mut table = table.borrow_mut();
+            match table.ids.get(name) {
+                Some(symbol) => *symbol,
+                None => table.insert(Box::leak(name.to_string().into_boxed_str())),
+            }
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        TABLE.with(|table| table.borrow().names[self.0 as usize])
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{COND, DOT, KEY, PREDEFINED, Symbol};
+
+    #[test]
+    fn names_are_interned_once() {
+        assert_eq!(Symbol::new("cond"), COND);
+        assert_eq!(Symbol::new("#:key"), KEY);
+        assert_eq!(DOT.name(), ".");
+        let name = String::from("interned-once");
+        let s = Symbol::new(&name);
+        assert_eq!(Symbol::new("interned-once"), s);
+        assert_ne!(Symbol::new("interned-twice"), s);
+        assert_eq!(s.name(), "interned-once");
+        assert_eq!(s.to_string(), "interned-once");
+        // The predefined names keep their ids
+        for (i, name) in PREDEFINED.iter().enumerate() {
+            assert_eq!(Symbol::new(name), Symbol(i as u32));
+        }
+    }
+}
@@ -1,73 +1,458 @@
+use crate::conditions;
+use crate::gc;
+use crate::maps::Map;
+use crate::number::Number;
+use crate::records::{Procedure, Record};
+use crate::symbols::Symbol;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::collections::LinkedList;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
-    Symbol(String), // Variables
-    Number(f64),    // Numbers
+    Symbol(Symbol),              // Variables
+    Local(Symbol, usize, usize), // A variable resolved to its frame depth and index, see `Environment::address`
+    Number(Number),              // Numbers
     String(String),
-    Boolean(bool), //
+    Char(char),
+    Keyword(String), // `:name`, evaluates to itself
+    Boolean(bool),   //
     #[allow(dead_code)]
-    Quote(Box<Expression>), // This is always a quasiquote! //
+    Quote(Rc<Expression>), // This is always a quasiquote! //
     #[allow(dead_code)]
-    Unquote(Box<Expression>), //
-    List(Vec<Expression>), // Expression -> Expression //
+    Unquote(Rc<Expression>), //
+    List(Rc<Vec<Expression>>), // Expression -> Expression, shared by every copy //
+    Lambda(Rc<Params>, Rc<Expression>, Scope), // Parameters, the partially evaluated body and where it was made
+    Vector(Rc<RefCell<Vec<Expression>>>),      // Mutable and shared by every copy
+    Map(Rc<RefCell<Map>>),                     // Likewise
+    Record(Rc<Record>), // Instance of a `define-record-type`, with mutable fields
+    Procedure(Rc<Procedure>), // Constructor, predicate, accessor or modifier of a record type
     Nil,
 }
 
+impl Expression {
+    pub fn list(v: Vec<Expression>) -> Expression {
+        Expression::List(Rc::new(v))
+    }
+
+    /* A list of the values, or nil if there are none. */
+    pub fn list_or_nil(v: Vec<Expression>) -> Expression {
+        if v.is_empty() {
+            Expression::Nil
+        } else {
+            Expression::list(v)
+        }
+    }
+
+    pub fn quote(e: Expression) -> Expression {
+        Expression::Quote(Rc::new(e))
+    }
+
+    /* The elements of a list value, quoted like `'(1 2)` or not, None if it isn't a list. */
+    pub fn elements(&self) -> Option<&[Expression]> {
+        match self {
+            Expression::List(v) => Some(v),
+            Expression::Nil => Some(&[]),
+            Expression::Quote(e) if matches!(**e, Expression::List(_) | Expression::Nil) => {
+                e.elements()
+            }
+            _ => None,
+        }
+    }
+
+    /* The name `type-of` gives the type of a value, records are named after their record type. */
+    pub fn type_name(&self) -> String {
+        let name = match self {
+            Expression::Symbol(_) | Expression::Local(..) => "symbolic",
+            Expression::Number(_) => "number",
+            Expression::String(_) => "string",
+            Expression::Char(_) => "char",
+            Expression::Keyword(_) => "keyword",
+            Expression::Boolean(_) => "boolean",
+            Expression::Quote(e) if matches!(**e, Expression::Symbol(_)) => "symbol",
+            Expression::Quote(e) => return e.type_name(),
+            Expression::Unquote(_) => "unquote",
+            Expression::List(_) => "list",
+            Expression::Lambda(..) | Expression::Procedure(_) => "procedure",
+            Expression::Vector(_) => "vector",
+            Expression::Map(_) => "hash",
+            Expression::Record(r) => &r.kind.name,
+            Expression::Nil => "null",
+        };
+        name.to_string()
+    }
+}
+
+/* Only values that can be map keys are hashed by content, the others all hash alike. */
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Expression::Symbol(s) | Expression::Local(s, ..) => s.hash(state),
+            Expression::String(s) | Expression::Keyword(s) => s.hash(state),
+            Expression::Number(n) => n.hash(state),
+            Expression::Char(c) => c.hash(state),
+            Expression::Boolean(b) => b.hash(state),
+            Expression::Quote(e) | Expression::Unquote(e) => e.hash(state),
+            Expression::List(v) => v.hash(state),
+            _ => (),
+        }
+    }
+}
+
+impl Eq for Expression {}
+
+// Characters written by name, like `#\space`
+pub const CHAR_NAMES: &[(&str, char)] = &[("space", ' '), ("newline", '\n'), ("tab", '\t')];
+
+// Parameter list of a user function: `(f a b #:optional (c 1) #:key (d 2) . rest)`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Params {
+    pub required: Vec<Symbol>,
+    pub optional: Vec<(Symbol, Expression)>, // Name and default value
+    pub key: Vec<(Symbol, Expression)>,      // Passed as `:name value`, with a default value
+    pub rest: Option<Symbol>,
+}
+
+impl Params {
+    pub fn names(&self) -> impl Iterator<Item = &Symbol> {
+        self.required
+            .iter()
+            .chain(self.optional.iter().map(|(name, _)| name))
+            .chain(self.key.iter().map(|(name, _)| name))
+            .chain(self.rest.iter())
+    }
+
+    pub fn only_required(&self) -> bool {
+        self.optional.is_empty() && self.key.is_empty() && self.rest.is_none()
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.required.len()
+            && (self.rest.is_some() || count <= self.required.len() + self.optional.len())
+    }
+
+    /* Describes the accepted number of positional arguments for error messages. */
+    pub fn describe(&self) -> String {
+        let min = self.required.len();
+        let max = (self.rest.is_none()).then_some(min + self.optional.len());
+        describe_arity(min, max)
+    }
+}
+
+/* Describes a number of arguments between `min` and `max`, no maximum meaning any number. */
+pub fn describe_arity(min: usize, max: Option<usize>) -> String {
+    match max {
+        None => format!("at least {}", min),
+        Some(max) if max == min => format!("{}", min),
+        Some(max) => format!("between {} and {}", min, max),
+    }
+}
+
+/* Checks that a call got between `min` and `max` arguments, `call` gives the whole call for errors. */
+pub fn check_arity(
+    min: usize,
+    max: Option<usize>,
+    args: &[Expression],
+    call: impl FnOnce() -> Expression,
+) -> Result<(), LispError> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        return Err(LispError::Arity(call(), describe_arity(min, max)));
+    }
+    Ok(())
+}
+
+/* Reads an index into a string or vector, which is a non-negative exact integer. */
+pub fn index(e: &Expression, call: &Expression) -> Result<usize, LispError> {
+    match e {
+        Expression::Number(Number::Integer(n)) if !n.is_negative() => n
+            .to_i64()
+            .map(|n| n as usize)
+            .ok_or_else(|| LispError::Range(call.clone(), "a smaller index".to_string())),
+        _ => Err(LispError::Type(
+            call.clone(),
+            "a non-negative exact integer".to_string(),
+        )),
+    }
+}
+
+// Errors raised while evaluating, each but an interruption carries the offending expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum LispError {
+    DivisionByZero(Expression),
+    Arity(Expression, String), // The call and the expected number of arguments
+    Type(Expression, String),  // The call and what was expected instead
+    Unbound(String, Expression), // The name and the expression it was used in
+    Range(Expression, String), // The call and the indices it accepts
+    Limit(Expression, String), // The expression being evaluated and the limit it went over
+    Interrupted,               // By Ctrl-C, wherever the evaluation was
+    Raised(Expression),        // A value raised with `raise` or `error`
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LispError::DivisionByZero(e) => write!(f, "division by zero in {}", e),
+            LispError::Arity(e, expected) => {
+                write!(
+                    f,
+                    "wrong number of arguments in {}, expected {}",
+                    e, expected
+                )
+            }
+            LispError::Type(e, expected) => write!(f, "wrong type in {}, expected {}", e, expected),
+            LispError::Range(e, expected) => {
+                write!(f, "index out of range in {}, expected {}", e, expected)
+            }
+            LispError::Limit(e, limit) => write!(f, "{} limit reached in {}", limit, e),
+            LispError::Interrupted => write!(f, "interrupted"),
+            LispError::Raised(value) => write!(f, "{}", conditions::describe(value)),
+            LispError::Unbound(name, Expression::Nil) => write!(f, "unbound variable {}", name),
+            LispError::Unbound(name, e) => write!(f, "unbound variable {} in {}", name, e),
+        }
+    }
+}
+
+/*
+ * A scope of local variables, linked to the scope it was made in.
+ * Frames are shared, so a closure keeps the frame it was made in alive after the call that made it returns.
+ * The variables are kept in the order they were bound, so they can be found by their index.
+ */
+pub struct Frame {
+    vars: RefCell<Vec<(Symbol, Expression)>>,
+    parent: Scope,
+}
+
+// The innermost frame of a chain of scopes, None is just the global scope
+pub type Scope = Option<Rc<Frame>>;
+
+// Frames are compared by identity, they may contain closures that refer back to them
+impl PartialEq for Frame {
+    fn eq(&self, other: &Frame) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Frame {
+    /* An empty frame inside `parent`, for scopes rebuilt outside of a call. */
+    pub fn new(parent: Scope) -> Rc<Frame> {
+        let frame = Rc::new(Frame {
+            vars: RefCell::new(Vec::new()),
+            parent,
+        });
+        gc::track_frame(&frame);
+        frame
+    }
+
+    pub fn parent(&self) -> &Scope {
+        &self.parent
+    }
+
+    /* The variables themselves, for the collector to look into without copying them. */
+    pub fn variables(&self) -> &RefCell<Vec<(Symbol, Expression)>> {
+        &self.vars
+    }
+
+    pub fn vars(&self) -> Vec<(Symbol, Expression)> {
+        self.vars.borrow().clone()
+    }
+
+    pub fn bind(&self, s: Symbol, e: Expression) {
+        self.vars.borrow_mut().push((s, e));
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame({:p})", self)
+    }
+}
+
 pub struct Environment {
-    scopes: LinkedList<HashMap<String, (Vec<String>, Expression)>>,
+    globals: HashMap<Symbol, Expression>,
+    current: Scope,
 }
 
 impl Environment {
     pub fn new() -> Environment {
-        let mut env = Environment {
-            scopes: LinkedList::new(),
-        };
-        env.add_scope();
-        env
+        Environment {
+            globals: HashMap::new(),
+            current: None,
+        }
     }
 
-    pub fn is_defined(&self, s: &str) -> bool {
-        for scope in self.scopes.iter().rev() {
-            if scope.contains_key(s) {
-                return true;
-            }
-        }
-        false
+    /* The frames from the innermost one outwards. */
+    fn frames(&self) -> impl Iterator<Item = &Rc<Frame>> {
+        std::iter::successors(self.current.as_ref(), |frame| frame.parent.as_ref())
+    }
+
+    pub fn is_defined(&self, s: Symbol) -> bool {
+        self.frames()
+            .any(|frame| frame.vars.borrow().iter().any(|(name, _)| *name == s))
+            || self.globals.contains_key(&s)
+    }
+
+    /* The value of `s` in the innermost scope binding it, None if it is unbound. */
+    pub fn find(&self, s: Symbol) -> Option<Expression> {
+        self.get_local(s).or_else(|| self.globals.get(&s).cloned())
+    }
+
+    pub fn get(&self, s: Symbol) -> Expression {
+        self.find(s).unwrap_or(Expression::Symbol(s))
     }
 
-    pub fn get(&self, s: &str) -> (Vec<String>, Expression) {
-        for scope in self.scopes.iter().rev() {
-            if let Some(result) = scope.get(s) {
-                return result.clone();
+    /* Looks a symbol up in every scope but the global one. */
+    pub fn get_local(&self, s: Symbol) -> Option<Expression> {
+        self.frames().find_map(|frame| {
+            frame
+                .vars
+                .borrow()
+                .iter()
+                .find(|(name, _)| *name == s)
+                .map(|(_, e)| e.clone())
+        })
+    }
+
+    /*
+     * The depth and index of the variable `s` if it is still symbolic, that is bound to itself
+     * while a body is being reduced. Other variables have already been replaced by their values.
+     */
+    pub fn address(&self, s: Symbol) -> Option<(usize, usize)> {
+        for (depth, frame) in self.frames().enumerate() {
+            let vars = frame.vars.borrow();
+            if let Some(index) = vars.iter().position(|(name, _)| *name == s) {
+                return matches!(&vars[index].1, Expression::Symbol(v) if *v == s)
+                    .then_some((depth, index));
             }
         }
-        (Vec::new(), Expression::Symbol(s.to_string()))
+        None
     }
 
-    pub fn local_push(&mut self, s: String, params: Vec<String>, e: Expression) {
-        if !self.scopes.is_empty() {
-            if let Some(scope) = self.scopes.back_mut() {
-                scope.insert(s, (params, e));
-            }
+    /*
+     * Finds the variable `s` at its lexical address. A body can run in other frames than the ones
+     * it was resolved in, for example when curried, so the name is checked and looked up if it differs.
+     */
+    pub fn lookup(&self, s: Symbol, depth: usize, index: usize) -> Expression {
+        if let Some(frame) = self.frames().nth(depth)
+            && let Some((name, e)) = frame.vars.borrow().get(index)
+            && *name == s
+        {
+            return e.clone();
         }
+        self.get(s)
     }
 
-    pub fn global_push(&mut self, s: String, params: Vec<String>, e: Expression) {
-        if !self.scopes.is_empty() {
-            if let Some(scope) = self.scopes.front_mut() {
-                scope.insert(s, (params, e));
+    pub fn local_push(&mut self, s: Symbol, e: Expression) {
+        match &self.current {
+            Some(frame) => {
+                let mut vars = frame.vars.borrow_mut();
+                match vars.iter_mut().find(|(name, _)| *name == s) {
+                    Some(var) => var.1 = e,
+                    None => vars.push((s, e)),
+                }
+            }
+            None => {
+                self.globals.insert(s, e);
             }
         }
     }
 
+    /* The global variables, sorted by name so they always come out in the same order. */
+    pub fn globals(&self) -> Vec<(Symbol, Expression)> {
+        let mut globals: Vec<_> = self.globals.iter().map(|(s, e)| (*s, e.clone())).collect();
+        globals.sort_by_key(|(s, _)| s.name());
+        globals
+    }
+
+    pub fn global_push(&mut self, s: Symbol, e: Expression) {
+        self.globals.insert(s, e);
+    }
+
     pub fn pop_scope(&mut self) {
-        self.scopes.pop_back();
+        if let Some(frame) = self.current.take() {
+            self.current = frame.parent.clone();
+        }
     }
 
     pub fn add_scope(&mut self) {
-        let scope = HashMap::new();
-        self.scopes.push_back(scope);
+        self.current = Some(Frame::new(self.current.take()));
+    }
+
+    /* The current scope, for a closure to keep. */
+    pub fn capture(&self) -> Scope {
+        self.current.clone()
+    }
+
+    /* Adds a scope inside `scope` rather than the current one, returns the scope to go back to with `restore`. */
+    pub fn enter(&mut self, scope: Scope) -> Scope {
+        let outer = std::mem::replace(&mut self.current, scope);
+        self.add_scope();
+        outer
+    }
+
+    pub fn restore(&mut self, scope: Scope) {
+        self.current = scope;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Environment, Expression};
+    use crate::symbols::Symbol;
+
+    #[test]
+    fn frames_nest_and_outlive_their_scope() {
+        let (x, y) = (Symbol::new("x"), Symbol::new("y"));
+        let mut env = Environment::new();
+        env.global_push(x, Expression::Boolean(false));
+        env.add_scope();
+        env.local_push(x, Expression::Boolean(true));
+        env.add_scope();
+        env.local_push(y, Expression::Nil);
+        // Inner scopes see the variables of the outer ones, and shadow the globals
+        assert_eq!(env.get(x), Expression::Boolean(true));
+        assert_eq!(env.get(y), Expression::Nil);
+
+        // A captured scope keeps its frames once the scopes are left
+        let closure = env.capture();
+        env.pop_scope();
+        assert_eq!(env.find(y), None);
+        env.pop_scope();
+        assert_eq!(env.get(x), Expression::Boolean(false));
+        let outer = env.enter(closure);
+        assert_eq!(env.get(y), Expression::Nil);
+        assert_eq!(env.get(x), Expression::Boolean(true));
+        // Binding in the entered scope leaves the captured frames alone
+        env.local_push(y, Expression::Boolean(true));
+        env.pop_scope();
+        assert_eq!(env.get(y), Expression::Nil);
+        env.restore(outer);
+        assert_eq!(env.get(y), Expression::Symbol(y));
+    }
+
+    #[test]
+    fn symbolic_variables_have_addresses() {
+        let (x, y, z) = (Symbol::new("x"), Symbol::new("y"), Symbol::new("z"));
+        let mut env = Environment::new();
+        env.add_scope();
+        env.local_push(x, Expression::Symbol(x));
+        env.local_push(y, Expression::Nil);
+        env.add_scope();
+        env.local_push(z, Expression::Symbol(z));
+        assert_eq!(env.address(z), Some((0, 0)));
+        assert_eq!(env.address(x), Some((1, 0)));
+        // Variables with a value and globals have no address
+        assert_eq!(env.address(y), None);
+        assert_eq!(env.address(Symbol::new("w")), None);
+        assert_eq!(env.lookup(x, 1, 0), Expression::Symbol(x));
+
+        // In other frames, the name no longer matches and is looked up instead
+        env.pop_scope();
+        env.local_push(x, Expression::Boolean(true));
+        assert_eq!(env.lookup(y, 0, 0), Expression::Nil);
+        assert_eq!(env.lookup(x, 1, 0), Expression::Boolean(true));
+        assert_eq!(env.lookup(z, 0, 0), Expression::Symbol(z));
     }
 }
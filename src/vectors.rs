@@ -0,0 +1,148 @@
+use crate::gc;
+use crate::number::Number;
+use crate::types::{self, Expression, LispError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Names of the vector builtins, `vector-map` is handled by the evaluator since it calls functions
+pub const FUNCTIONS: &[&str] = &[
+    "vector",
+    "make-vector",
+    "vector-ref",
+    "vector-set!",
+    "vector-length",
+    "vector->list",
+    "list->vector",
+    "vector-map",
+];
+
+/* The smallest and largest number of arguments of a builtin, None if there is no limit. */
+pub fn arity(name: &str) -> (usize, Option<usize>) {
+    match name {
+        "vector" => (0, None),
+        "make-vector" => (1, Some(2)),
+        "vector-ref" => (2, Some(2)),
+        "vector-set!" => (3, Some(3)),
+        "vector-map" => (2, None),
+        _ => (1, Some(1)),
+    }
+}
+
+pub fn new(elements: Vec<Expression>) -> Expression {
+    let vector = Expression::Vector(Rc::new(RefCell::new(elements)));
+    gc::track_value(&vector);
+    vector
+}
+
+pub fn vector(
+    e: &Expression,
+    call: &Expression,
+) -> Result<Rc<RefCell<Vec<Expression>>>, LispError> {
+    match e {
+        Expression::Vector(v) => Ok(v.clone()),
+        _ => Err(LispError::Type(call.clone(), "a vector".to_string())),
+    }
+}
+
+/* About how many bytes the result of the builtin takes when it can be huge, zero otherwise. */
+pub fn allocates(name: &str, args: &[Expression]) -> usize {
+    match (name, args.first()) {
+        ("make-vector", Some(Expression::Number(Number::Integer(n)))) if !n.is_negative() => {
+            n.to_i64().map_or(usize::MAX, |len| {
+                (len as usize).saturating_mul(size_of::<Expression>())
+            })
+        }
+        _ => 0,
+    }
+}
+
+fn index(e: &Expression, len: usize, call: &Expression) -> Result<usize, LispError> {
+    match types::index(e, call) {
+        Ok(i) if i < len => Ok(i),
+        Ok(_) | Err(LispError::Range(..)) => {
+            Err(LispError::Range(call.clone(), format!("below {}", len)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/*
+ * Applies the vector builtin `name` to fully known arguments, the arity must already be checked.
+ * `call` is the whole call, used in errors.
+ */
+pub fn call(name: &str, args: &[Expression], call: &Expression) -> Result<Expression, LispError> {
+    let ret = match name {
+        "vector" => new(args.to_vec()),
+        "make-vector" => {
+            let len = match &args[0] {
+                Expression::Number(Number::Integer(n)) if !n.is_negative() => n.to_i64(),
+                _ => None,
+            };
+            let Some(len) = len else {
+                return Err(LispError::Type(
+                    call.clone(),
+                    "a non-negative exact integer".to_string(),
+                ));
+            };
+            let fill = args.get(1).cloned().unwrap_or(Expression::Nil);
+            new(vec![fill; len as usize])
+        }
+        "vector-ref" => {
+            let v = vector(&args[0], call)?;
+            let v = v.borrow();
+            v[index(&args[1], v.len(), call)?].clone()
+        }
+        "vector-set!" => {
+            let v = vector(&args[0], call)?;
+            let i = index(&args[1], v.borrow().len(), call)?;
+            v.borrow_mut()[i] = args[2].clone();
+            Expression::Nil
+        }
+        "vector-length" => {
+            Expression::Number(Number::from(vector(&args[0], call)?.borrow().len() as i64))
+        }
+        "vector->list" => Expression::list_or_nil(vector(&args[0], call)?.borrow().clone()),
+        "list->vector" => match args[0].elements() {
+            Some(elements) => new(elements.to_vec()),
+            None => return Err(LispError::Type(call.clone(), "a list".to_string())),
+        },
+        _ => call.clone(),
+    };
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::{self, Evaluator};
+    use crate::{parser, scanner};
+
+    fn run(program: &str, eval: &mut Evaluator) -> String {
+        let expressions = parser::parse(scanner::scan(program));
+        evaluator::evaluate(expressions, eval).unwrap().to_string()
+    }
+
+    #[test]
+    fn vectors_holding_themselves() {
+        let mut eval = Evaluator::new();
+        run("(define v (vector 1 2)) (vector-set! v 0 v)", &mut eval);
+        assert_eq!(run("(vector-length v)", &mut eval), "2");
+        assert_eq!(run("(vector-ref v 1)", &mut eval), "2");
+        assert_eq!(run("(vector-length (vector-ref v 0))", &mut eval), "2");
+        assert_eq!(run("v", &mut eval), "#(#<cycle> 2)");
+        assert_eq!(run("(vector v)", &mut eval), "#(#(#<cycle> 2))");
+    }
+
+    #[test]
+    fn list_to_vector() {
+        let mut eval = Evaluator::new();
+        assert_eq!(run("(list->vector '(1 2))", &mut eval), "#(1 2)");
+        assert_eq!(run("(list->vector '())", &mut eval), "#()");
+        assert_eq!(
+            run(
+                "(vector->list (list->vector (vector->list [1 2])))",
+                &mut eval
+            ),
+            "(1 2)"
+        );
+    }
+}
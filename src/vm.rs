@@ -0,0 +1,662 @@
+use crate::compiler::{self, Function, Op};
+use crate::conditions;
+use crate::evaluator::{self, Evaluator};
+use crate::types::Scope;
+use crate::types::{Expression, LispError, Params};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/*
+ * Stack virtual machine running compiled programs, an alternative to the tree walker for concrete code.
+ * Nothing is reduced ahead of time, so symbolic values are only passed around as they are, except
+ * in curry mode where the tree walker specialises a function on the arguments of an under-applied call.
+ * Closures are ordinary lambda values sharing the environment of the evaluator, the code of a closure
+ * is found again by its body, functions made elsewhere are compiled when they are first called.
+ * The code is kept as long as something could still call it.
+ */
+pub struct Machine {
+    functions: HashMap<*const Expression, Rc<Function>>,
+}
+
+// A call being run
+struct Call {
+    function: Rc<Function>,
+    ip: usize,    // The next instruction
+    base: usize,  // Where the values of the call start on the stack
+    outer: Scope, // The scope to go back to when it returns
+}
+
+// A guard whose body is being run
+struct Handler {
+    function: Expression, // A closure taking what was raised
+    target: usize,        // Where the call to it is, in the call of the guard
+    calls: usize,         // How many calls there were, and values on the stack
+    stack: usize,
+    scope: Scope,
+}
+
+struct State {
+    stack: Vec<Expression>,
+    calls: Vec<Call>,
+    handlers: Vec<Handler>,
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        Machine {
+            functions: HashMap::new(),
+        }
+    }
+
+    /* Compiles and runs top level expressions, returns the value of the last one. */
+    pub fn run(
+        &mut self,
+        expressions: Vec<Expression>,
+        eval: &mut Evaluator,
+    ) -> Result<Expression, LispError> {
+        let script = Rc::new(compiler::compile(&expressions, eval)?);
+        self.register(&script);
+        let scope = eval.env.capture();
+        evaluator::begin(eval);
+        let ret = self.execute(script, eval);
+        // An error leaves the scopes of the calls it went through
+        eval.env.restore(scope);
+        self.release();
+        ret
+    }
+
+    /*
+     * Forgets the functions that can't be called anymore, no closure shares their body and no function
+     * they are nested in is kept. Their body goes with them, so its address can't be found again.
+     */
+    fn release(&mut self) {
+        loop {
+            let count = self.functions.len();
+            self.functions.retain(|_, function| {
+                Rc::strong_count(function) > 1 || Rc::strong_count(&function.body) > 1
+            });
+            if self.functions.len() == count {
+                break;
+            }
+        }
+    }
+
+    /* Remembers the functions nested in a compiled one by their body. */
+    fn register(&mut self, function: &Function) {
+        for nested in function.chunk.functions.iter() {
+            self.functions
+                .insert(Rc::as_ptr(&nested.body), nested.clone());
+            self.register(nested);
+        }
+    }
+
+    /* The compiled code of a body, if a closure of it has been compiled or installed. */
+    pub fn compiled(&self, body: &Rc<Expression>) -> Option<&Rc<Function>> {
+        self.functions.get(&Rc::as_ptr(body))
+    }
+
+    /* Adds a function compiled elsewhere, for example loaded from an image. */
+    pub fn install(&mut self, function: Rc<Function>) {
+        self.register(&function);
+        self.functions.insert(Rc::as_ptr(&function.body), function);
+    }
+
+    /* The code of a closure, compiled now if it was made by the tree walker. */
+    fn function(
+        &mut self,
+        params: &Params,
+        body: &Rc<Expression>,
+        eval: &Evaluator,
+    ) -> Result<Rc<Function>, LispError> {
+        if let Some(function) = self.functions.get(&Rc::as_ptr(body)) {
+            return Ok(function.clone());
+        }
+        let function = Rc::new(compiler::compile_function(params, body, Vec::new(), eval)?);
+        self.install(function.clone());
+        Ok(function)
+    }
+
+    fn execute(
+        &mut self,
+        script: Rc<Function>,
+        eval: &mut Evaluator,
+    ) -> Result<Expression, LispError> {
+        let mut state = State {
+            stack: Vec::new(),
+            calls: vec![Call {
+                function: script,
+                ip: 0,
+                base: 0,
+                outer: eval.env.capture(),
+            }],
+            handlers: Vec::new(),
+        };
+        loop {
+            match self.instruction(&mut state, eval) {
+                Ok(None) => (),
+                Ok(Some(value)) => return Ok(value),
+                Err(err) => catch(&mut state, err, eval)?,
+            }
+        }
+    }
+
+    /* Runs the next instruction, returns the value of the script once it returns. */
+    fn instruction(
+        &mut self,
+        state: &mut State,
+        eval: &mut Evaluator,
+    ) -> Result<Option<Expression>, LispError> {
+        let State {
+            stack,
+            calls,
+            handlers,
+        } = state;
+        let call = calls.last_mut().unwrap();
+        let op = call.function.chunk.code[call.ip].clone();
+        call.ip += 1;
+        match op {
+            Op::Constant(i) => stack.push(call.function.chunk.constants[i].clone()),
+            Op::Local(s, depth, index) => stack.push(eval.env.lookup(s, depth, index)),
+            Op::Variable(s) => match eval.env.find(s) {
+                Some(value) => stack.push(value),
+                // Nothing is reduced ahead of time, so strict mode finds a free variable once it is used
+                None => {
+                    evaluator::check_symbolic(s, eval)?;
+                    stack.push(Expression::Symbol(s));
+                }
+            },
+            Op::Define(s) => eval.env.global_push(s, stack.last().unwrap().clone()),
+            Op::Closure(i) => {
+                let function = call.function.chunk.functions[i].clone();
+                let mut params = function.params.clone();
+                let count = params.optional.len() + params.key.len();
+                let defaults = stack.split_off(stack.len() - count);
+                let slots = params.optional.iter_mut().chain(params.key.iter_mut());
+                for ((_, default), value) in slots.zip(defaults) {
+                    *default = value;
+                }
+                stack.push(Expression::Lambda(
+                    Rc::new(params),
+                    function.body.clone(),
+                    eval.env.capture(),
+                ));
+            }
+            Op::Builtin(name, count) => {
+                // A name the program binds itself shadows the builtin, like in the tree walker
+                if let Some(function) = eval.env.find(name) {
+                    stack.insert(stack.len() - count, function);
+                    self.call(stack, calls, count, false, eval)?;
+                    return Ok(None);
+                }
+                let mut vexp = vec![Expression::Symbol(name)];
+                vexp.extend(stack.drain(stack.len() - count..));
+                evaluator::step(eval, || Expression::list(vexp.clone()))?;
+                stack.push(evaluator::apply_builtin(name, vexp, eval)?);
+            }
+            Op::Call(count) => self.call(stack, calls, count, false, eval)?,
+            Op::TailCall(count) => self.call(stack, calls, count, true, eval)?,
+            Op::Jump(target) => call.ip = target,
+            Op::JumpIfFalse(target) => {
+                if stack.pop() == Some(Expression::Boolean(false)) {
+                    call.ip = target;
+                }
+            }
+            Op::Guard(target) => {
+                let function = stack.pop().unwrap();
+                handlers.push(Handler {
+                    function,
+                    target,
+                    calls: calls.len(),
+                    stack: stack.len(),
+                    scope: eval.env.capture(),
+                });
+            }
+            Op::EndGuard => {
+                handlers.pop();
+            }
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Return => {
+                let value = stack.pop().unwrap_or(Expression::Nil);
+                let call = calls.pop().unwrap();
+                stack.truncate(call.base);
+                eval.env.restore(call.outer);
+                if calls.is_empty() {
+                    return Ok(Some(value));
+                }
+                evaluator::leave_call(eval);
+                stack.push(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /* Pops the arguments and the function below them and calls it, a tail call replaces the current call. */
+    fn call(
+        &mut self,
+        stack: &mut Vec<Expression>,
+        calls: &mut Vec<Call>,
+        count: usize,
+        tail: bool,
+        eval: &mut Evaluator,
+    ) -> Result<(), LispError> {
+        let vexp = stack.split_off(stack.len() - count - 1);
+        if let Expression::Procedure(_) = &vexp[0] {
+            stack.push(evaluator::reduce_record(vexp, eval)?);
+            return Ok(());
+        }
+        let Expression::Lambda(params, body, scope) = &vexp[0] else {
+            // Not a function, so the call is left as it is
+            stack.push(Expression::list(vexp));
+            return Ok(());
+        };
+        if let Some(curried) = evaluator::curried(&vexp, params, body, scope, eval)? {
+            stack.push(curried);
+            return Ok(());
+        }
+        let function = self.function(params, body, eval)?;
+        evaluator::step(eval, || Expression::list(vexp.clone()))?;
+        if tail {
+            let call = calls.pop().unwrap();
+            stack.truncate(call.base);
+            eval.env.restore(call.outer);
+            evaluator::leave_call(eval);
+        }
+        evaluator::enter_call(&vexp, eval)?;
+        let args = vexp[1..].to_vec();
+        let outer = match evaluator::bind_call(&vexp, params, scope, args, eval) {
+            Ok(outer) => outer,
+            Err(err) => {
+                evaluator::leave_call(eval);
+                return Err(err);
+            }
+        };
+        calls.push(Call {
+            function,
+            ip: 0,
+            base: stack.len(),
+            outer,
+        });
+        Ok(())
+    }
+}
+
+/*
+ * Hands an error to the innermost guard, unless it can't be caught. The calls and values since the guard
+ * are dropped and its handler is called with what was raised.
+ */
+fn catch(state: &mut State, err: LispError, eval: &mut Evaluator) -> Result<(), LispError> {
+    let Some(handler) = state.handlers.pop() else {
+        return Err(err);
+    };
+    let Some(raised) = conditions::from_error(&err) else {
+        return Err(err);
+    };
+    while state.calls.len() > handler.calls {
+        state.calls.pop();
+        evaluator::leave_call(eval);
+    }
+    eval.env.restore(handler.scope);
+    state.stack.truncate(handler.stack);
+    state.stack.push(handler.function);
+    state.stack.push(raised);
+    state.calls.last_mut().unwrap().ip = handler.target;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Machine;
+    use crate::evaluator::{self, Evaluator};
+    use crate::{parser, scanner};
+
+    /* Runs a program with the tree walker and the virtual machine, which have to agree. Returns what it printed. */
+    fn agree(program: &str) -> String {
+        let tree =
+            evaluator::evaluate(parser::parse(scanner::scan(program)), &mut Evaluator::new());
+        let vm = Machine::new().run(parser::parse(scanner::scan(program)), &mut Evaluator::new());
+        let tree = tree.map(|e| e.to_string()).map_err(|err| err.to_string());
+        let vm = vm.map(|e| e.to_string()).map_err(|err| err.to_string());
+        assert_eq!(tree, vm, "{}", program);
+        tree.unwrap_or_else(|err| err)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(agree("(+ 1 (* 2 3) (- 10 4) (/ 9 3))"), "16");
+        assert_eq!(agree("(/ 1 3)"), "1/3");
+        assert_eq!(agree("(max 1 (expt 2 10) 3)"), "1024");
+        assert_eq!(agree("(= 2 (+ 1 1))"), "true");
+        assert_eq!(agree("(mod 7 0.0)"), "+nan.0");
+        assert_eq!(agree("(quotient 7 2.0)"), "3.0");
+        assert_eq!(
+            agree("(quotient 7.5 2)"),
+            "wrong type in (quotient 7.5 2), expected integers"
+        );
+    }
+
+    #[test]
+    fn definitions() {
+        assert_eq!(agree("(define x 5) (define y (* x 2)) (+ x y)"), "15");
+        assert_eq!(agree("(define (sq x) (* x x)) (sq (sq 3))"), "81");
+    }
+
+    #[test]
+    fn recursion() {
+        let factorial = "(define (fact n) (cond ((= n 0) 1) (t (* n (fact (- n 1))))))";
+        assert_eq!(
+            agree(&format!("{} (fact 20)", factorial)),
+            "2432902008176640000"
+        );
+        let fib =
+            "(define (fib n) (cond ((= n 0) 0) ((= n 1) 1) (t (+ (fib (- n 1)) (fib (- n 2))))))";
+        assert_eq!(agree(&format!("{} (fib 12)", fib)), "144");
+        let even = "(define (even? n) (cond ((= n 0) true) (t (odd? (- n 1)))))
+                    (define (odd? n) (cond ((= n 0) false) (t (even? (- n 1)))))";
+        assert_eq!(agree(&format!("{} (even? 31)", even)), "false");
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(
+            agree("(define (adder k) (lambda (x) (+ x k))) ((adder 3) 4)"),
+            "7"
+        );
+        assert_eq!(
+            agree(
+                "(define (compose g h) (lambda (x) (g (h x)))) ((compose (lambda (x) (* x 2)) (lambda (x) (+ x 1))) 5)"
+            ),
+            "12"
+        );
+        assert_eq!(
+            agree("(define (curry3 a) (lambda (b) (lambda (c) (list a b c)))) (((curry3 1) 2) 3)"),
+            "(list 1 2 3)"
+        );
+    }
+
+    #[test]
+    fn parameters() {
+        let opt = "(define (scale x #:optional (by 10)) (* x by))";
+        assert_eq!(agree(&format!("{} (+ (scale 2) (scale 2 3))", opt)), "26");
+        let key = "(define (area w #:key (h 2)) (* w h))";
+        assert_eq!(agree(&format!("{} (+ (area 3) (area 3 :h 4))", key)), "18");
+        assert_eq!(agree("(define (count . xs) xs) (count 1 2 3)"), "(1 2 3)");
+    }
+
+    #[test]
+    fn builtins() {
+        assert_eq!(
+            agree("(string-append \"ab\" (number->string (string-length \"abc\")))"),
+            "\"ab3\""
+        );
+        assert_eq!(
+            agree(
+                "(define v (make-vector 3 0)) (vector-set! v 1 5) (vector->list (vector-map (lambda (x) (+ x 1)) v))"
+            ),
+            "(1 6 1)"
+        );
+        assert_eq!(
+            agree(
+                "(define m (hash :a 1)) (hash-set! m :b 2) (+ (hash-ref m :a) (hash-ref m :b) (hash-count m))"
+            ),
+            "5"
+        );
+        assert_eq!(
+            agree(
+                "(define-record-type point (make-point x y) point? (x point-x) (y point-y set-point-y!)) (define p (make-point 1 2)) (set-point-y! p 5) (list (point? p) (point-x p) (point-y p))"
+            ),
+            "(list true 1 5)"
+        );
+        assert_eq!(
+            agree("(list (number? 1) (string? 2) (type-of \"s\"))"),
+            "(list true false 'string)"
+        );
+    }
+
+    #[test]
+    fn records() {
+        let point = "(define-record-type point (make-point x y) point? (x point-x) (y point-y))";
+        assert_eq!(
+            agree(&format!("{} (point-x 5)", point)),
+            "wrong type in (point-x 5), expected a point"
+        );
+        assert_eq!(
+            agree(&format!("{} (point-x)", point)),
+            "wrong number of arguments in (point-x), expected 1"
+        );
+        assert_eq!(
+            agree(&format!("{} (list point-x (procedure? point-y))", point)),
+            "(list point-x true)"
+        );
+        // Another type of the same name has nothing to do with the first one
+        assert_eq!(
+            agree(&format!(
+                "{} (define p (make-point 1 2)) {} (list (point? p) (point? (make-point 1 2)))",
+                point, point
+            )),
+            "(list false true)"
+        );
+        // The procedures are made of nothing the program could call itself
+        assert_eq!(
+            agree(&format!("{} (record-ref 'point 0 (make-point 1 2))", point)),
+            "(record-ref 'point 0 #<point x=1 y=2>)"
+        );
+    }
+
+    #[test]
+    fn predicates_on_quoted_lists() {
+        assert_eq!(
+            agree("(vector (list? '(1 2)) (pair? '(1)) (list? '()) (pair? '()) (pair? 'a))"),
+            "#(true true true false false)"
+        );
+        assert_eq!(
+            agree("(vector (list? (vector->list [1])) (pair? (vector->list [])) (list? [1]))"),
+            "#(true false false)"
+        );
+        assert_eq!(agree("(define xs '(1 2)) (pair? xs)"), "true");
+    }
+
+    #[test]
+    fn bindings_shadow_builtins() {
+        assert_eq!(agree("(define (abs x) 42) (abs -1)"), "42");
+        assert_eq!(
+            agree("(define (twice max x) (max (max x))) (twice (lambda (y) (* y 2)) 5)"),
+            "20"
+        );
+        assert_eq!(agree("(define (vector . xs) xs) (vector 1 2)"), "(1 2)");
+    }
+
+    #[test]
+    fn residual_calls_keep_their_argument_order() {
+        assert_eq!(agree("(expt x 2)"), "(expt x 2)");
+        assert_eq!(agree("(expt 2 x)"), "(expt 2 x)");
+        assert_eq!(agree("(quotient 7 n)"), "(quotient 7 n)");
+        assert_eq!(agree("(atan y 1)"), "(atan y 1)");
+        assert_eq!(agree("(- x 2)"), "(- x 2)");
+        assert_eq!(agree("(/ x 2)"), "(/ x 2)");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            agree("(define (half x) (/ 2 x)) (half 0)"),
+            "division by zero in (/ 2 0)"
+        );
+        assert_eq!(
+            agree("(vector-ref (vector 1 2) 5)"),
+            "index out of range in (vector-ref #(1 2) 5), expected below 2"
+        );
+    }
+
+    #[test]
+    fn guards() {
+        assert_eq!(
+            agree("(guard (e ((condition? e) (condition-kind e))) (/ 1 0))"),
+            "'division-by-zero"
+        );
+        let deep = "(define (deep n) (cond ((= n 0) (raise 'bottom)) (t (+ 1 (deep (- n 1))))))";
+        assert_eq!(
+            agree(&format!(
+                "{} (guard (e ((= e 'bottom) 'caught)) (deep 20))",
+                deep
+            )),
+            "'caught"
+        );
+        assert_eq!(
+            agree("(define (k x) (guard (e ((= e 1) 'one)) (+ x (raise (* x 2))))) (k 4)"),
+            "uncaught 8"
+        );
+        assert_eq!(agree("(error \"bad\" 1 2)"), "bad: 1 2");
+        assert_eq!(
+            agree("(guard (e (t (condition-irritants e))) (error \"bad\"))"),
+            "nil"
+        );
+        // A record type of the same name and fields makes no conditions
+        assert_eq!(
+            agree(
+                "(define-record-type condition (make-condition kind message irritants) c? (kind k) (message m) (irritants i))
+                 (condition? (make-condition 'error \"fake\" nil))"
+            ),
+            "false"
+        );
+    }
+
+    #[test]
+    fn numeric_builtins_raise_arity_and_type_errors() {
+        let kind = |call: &str| {
+            agree(&format!(
+                "(guard (e ((condition? e) (condition-kind e))) {})",
+                call
+            ))
+        };
+        assert_eq!(kind("(sqrt 1 2)"), "'arity");
+        assert_eq!(kind("(expt 2)"), "'arity");
+        assert_eq!(kind("(-)"), "'arity");
+        assert_eq!(kind("(sqrt \"a\")"), "'type");
+        assert_eq!(kind("(+ \"a\" 1)"), "'type");
+        assert_eq!(kind("(* 2 'b)"), "'type");
+        assert_eq!(kind("(/ [1] 2)"), "'type");
+        assert_eq!(kind("(max 1 \"a\")"), "'type");
+        // Only once it runs, and only with every argument known
+        assert_eq!(
+            agree("(define (k x) (cond ((= x 0) (sqrt \"a\")) (t x))) (k 3)"),
+            "3"
+        );
+        assert_eq!(agree("(+ x \"a\")"), "(+ x \"a\")");
+    }
+
+    /* Runs a program on both the tree walker and the virtual machine with the limits or modes `limit` sets. */
+    fn limited(program: &str, limit: fn(&mut Evaluator)) -> [String; 2] {
+        let (mut tree, mut vm) = (Evaluator::new(), Evaluator::new());
+        limit(&mut tree);
+        limit(&mut vm);
+        let program = parser::parse(scanner::scan(program));
+        [
+            evaluator::evaluate(program.clone(), &mut tree),
+            Machine::new().run(program, &mut vm),
+        ]
+        .map(|result| result.map_or_else(|err| err.to_string(), |e| e.to_string()))
+    }
+
+    #[test]
+    fn limits() {
+        let spin = "(define (spin n) (spin (+ n 1))) (spin 0)";
+        for result in limited(spin, |eval| eval.fuel = Some(1000)) {
+            assert!(result.starts_with("fuel limit reached"), "{}", result);
+        }
+        // A guard can't catch a limit, its handler would only run into it again
+        let guarded = "(define (spin n) (spin (+ n 1))) (guard (e (t 'caught)) (spin 0))";
+        for result in limited(guarded, |eval| eval.fuel = Some(1000)) {
+            assert!(result.starts_with("fuel limit reached"), "{}", result);
+        }
+        let deep = "(define (deep n) (cond ((= n 0) 0) (t (+ 1 (deep (- n 1))))))";
+        assert_eq!(
+            limited(&format!("{} (deep 50)", deep), |eval| eval.max_depth =
+                Some(100)),
+            ["50", "50"]
+        );
+        for result in limited(&format!("{} (deep 1000)", deep), |eval| {
+            eval.max_depth = Some(100)
+        }) {
+            assert!(
+                result.starts_with("recursion depth limit reached"),
+                "{}",
+                result
+            );
+        }
+        let grow = "(define (grow s n)
+                      (cond ((= n 0) (string-length s)) (t (grow (string-append s s) (- n 1)))))";
+        assert_eq!(
+            limited(&format!("{} (grow \"ab\" 10)", grow), |eval| {
+                eval.max_heap = Some(1_000_000)
+            }),
+            ["2048", "2048"]
+        );
+        // Huge values are refused before they are allocated
+        for program in [
+            "(make-vector 100000000000000 0)",
+            "(expt 3 100000000000)",
+            &format!("{} (grow \"ab\" 60)", grow),
+        ] {
+            for result in limited(program, |eval| eval.max_heap = Some(1_000_000)) {
+                assert!(result.starts_with("heap limit reached"), "{}", result);
+            }
+        }
+        assert_eq!(
+            limited("(vector-length (make-vector 1000 0))", |eval| {
+                eval.max_heap = Some(1_000_000)
+            }),
+            ["1000", "1000"]
+        );
+    }
+
+    #[test]
+    fn curry_and_strict_modes() {
+        let add3 = "(define (add3 a b c) (+ a b c)) (define inc (add3 1 0))";
+        assert_eq!(
+            limited(&format!("{} (list inc (inc 41))", add3), |eval| eval
+                .curry =
+                true),
+            [
+                "(list (lambda (c) (+ 1 c)) 42)",
+                "(list (lambda (c) (+ 1 c)) 42)"
+            ]
+        );
+        let [tree, vm] = limited("(+ y 1)", |eval| eval.strict = true);
+        assert_eq!(tree, "unbound variable y in (+ y 1)");
+        assert_eq!(vm, "unbound variable y");
+        assert_eq!(
+            limited("(symbol? 'y)", |eval| eval.strict = true),
+            ["true", "true"]
+        );
+    }
+
+    #[test]
+    fn releases_functions_nothing_calls() {
+        let mut eval = Evaluator::new();
+        let mut machine = Machine::new();
+        let mut run = |program: &str| {
+            let program = parser::parse(scanner::scan(program));
+            machine.run(program, &mut eval).unwrap();
+            machine.functions.len()
+        };
+        assert_eq!(run("(define (sq x) (* x x)) (sq 3)"), 1);
+        assert_eq!(run("(define (sq x) (+ x x)) (sq 3)"), 1);
+        // The inner function stays with the outer one, then with the closure made of it
+        assert_eq!(run("(define (adder k) (lambda (x) (+ x k)))"), 3);
+        assert_eq!(run("(define add3 (adder 3)) (define adder nil)"), 2);
+        assert_eq!(run("(add3 4)"), 2);
+        assert_eq!(run("(define add3 nil)"), 1);
+        assert_eq!(run("((lambda (x) (* x 2)) 5)"), 1);
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_space() {
+        let program = "(define (count n acc) (cond ((= n 0) acc) (t (count (- n 1) (+ acc 1)))))
+                       (count 100000 0)";
+        let mut eval = Evaluator::new();
+        let result = Machine::new().run(parser::parse(scanner::scan(program)), &mut eval);
+        assert_eq!(result.unwrap().to_string(), "100000");
+    }
+}